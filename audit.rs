@@ -0,0 +1,69 @@
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+thread_local! {
+    static EVENTS: RefCell<StableBTreeMap<u64, AuditEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::AUDIT_MEMORY_ID)));
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum AuditAction {
+    // The user exported the data stored about them.
+    DataExported,
+    // The user erased their data, which removed the given number of
+    // templates.
+    DataDeleted { templates: u64 },
+}
+
+/// A record of a user exercising their data rights. Kept after the user's
+/// data is deleted as evidence of the deletion.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuditEvent {
+    pub principal: Principal,
+    pub action: AuditAction,
+    pub at: u64,
+}
+
+impl Storable for AuditEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn record(principal: Principal, action: AuditAction, at: u64) {
+    EVENTS.with_borrow_mut(|events| {
+        let id = events.last_key_value().map_or(0, |(id, _)| id + 1);
+        events.insert(
+            id,
+            AuditEvent {
+                principal,
+                action,
+                at,
+            },
+        );
+    });
+}
+
+pub fn events() -> Vec<AuditEvent> {
+    EVENTS.with_borrow(|events| events.iter().map(|(_, event)| event).collect())
+}
+
+/// Returns the events of the given principal.
+pub fn events_of(principal: &Principal) -> Vec<AuditEvent> {
+    EVENTS.with_borrow(|events| {
+        events
+            .iter()
+            .map(|(_, event)| event)
+            .filter(|event| event.principal == *principal)
+            .collect()
+    })
+}
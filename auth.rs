@@ -0,0 +1,197 @@
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+thread_local! {
+    static ADMINS: RefCell<StableBTreeMap<Principal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::ADMINS_MEMORY_ID)));
+    static ROLES: RefCell<StableBTreeMap<Principal, Roles, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::ROLES_MEMORY_ID)));
+}
+
+/// A role grants access to a group of privileged endpoints. Admins implicitly
+/// hold every role.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    // Manages admins and roles.
+    Admin,
+    // Uploads and loads the ONNX models.
+    ModelManager,
+    // Controls enrollment.
+    EnrollmentManager,
+    // Reads enrollment and recognition results.
+    Auditor,
+}
+
+// The admin before admins were stored, see `migrate_legacy_admin`.
+const LEGACY_ADMIN: &str = "4s4hz-og66m-hypzp-uxv6q-addgn-hshem-dnvln-uhy7t-h3hsc-pmajb-mqe";
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct Roles(Vec<Role>);
+
+impl Storable for Roles {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Returns true if the given principal is an admin. Controllers of the
+/// canister are always admins.
+pub fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal) || ADMINS.with_borrow(|a| a.contains_key(principal))
+}
+
+/// Returns an error unless the caller is an admin.
+pub fn require_admin() -> Result<(), String> {
+    if is_admin(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Unauthorized: only an admin can perform this action".to_string())
+    }
+}
+
+/// Returns true if the given principal holds the given role.
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    if is_admin(principal) {
+        return true;
+    }
+    role != Role::Admin
+        && ROLES.with_borrow(|r| {
+            r.get(principal)
+                .is_some_and(|roles| roles.0.contains(&role))
+        })
+}
+
+/// Returns an error unless the caller holds the given role.
+pub fn require_role(role: Role) -> Result<(), String> {
+    if has_role(&ic_cdk::caller(), role) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Unauthorized: the {:?} role is required to perform this action",
+            role
+        ))
+    }
+}
+
+/// Returns the roles of the given principal.
+pub fn roles(principal: &Principal) -> Vec<Role> {
+    if is_admin(principal) {
+        return vec![
+            Role::Admin,
+            Role::ModelManager,
+            Role::EnrollmentManager,
+            Role::Auditor,
+        ];
+    }
+    ROLES.with_borrow(|r| r.get(principal).unwrap_or_default().0)
+}
+
+/// Returns all principals with explicitly granted roles, including admins,
+/// once each.
+pub fn all_roles() -> Vec<(Principal, Vec<Role>)> {
+    let mut result: BTreeMap<Principal, Vec<Role>> = admins()
+        .into_iter()
+        .map(|admin| (admin, vec![Role::Admin]))
+        .collect();
+    ROLES.with_borrow(|r| {
+        for (principal, roles) in r.iter() {
+            result.entry(principal).or_default().extend(roles.0);
+        }
+    });
+    result.into_iter().collect()
+}
+
+pub fn grant_role(principal: Principal, role: Role) -> Result<(), String> {
+    if role == Role::Admin {
+        return add_admin(principal);
+    }
+    if principal == Principal::anonymous() {
+        return Err("The anonymous principal cannot hold a role".to_string());
+    }
+    ROLES.with_borrow_mut(|r| {
+        let mut roles = r.get(&principal).unwrap_or_default();
+        if !roles.0.contains(&role) {
+            roles.0.push(role);
+            r.insert(principal, roles);
+        }
+    });
+    Ok(())
+}
+
+pub fn revoke_role(principal: &Principal, role: Role) -> Result<(), String> {
+    if role == Role::Admin {
+        return remove_admin(principal);
+    }
+    ROLES.with_borrow_mut(|r| {
+        let mut roles = r
+            .get(principal)
+            .filter(|roles| roles.0.contains(&role))
+            .ok_or_else(|| format!("{} does not hold the {:?} role", principal, role))?;
+        roles.0.retain(|held| *held != role);
+        if roles.0.is_empty() {
+            r.remove(principal);
+        } else {
+            r.insert(*principal, roles);
+        }
+        Ok(())
+    })
+}
+
+/// Returns all admins stored in the state. Controllers are not included.
+pub fn admins() -> Vec<Principal> {
+    ADMINS.with_borrow(|a| a.iter().map(|(admin, _)| admin).collect())
+}
+
+/// Replaces the stored admins with the given ones.
+pub fn set_admins(admins: Vec<Principal>) -> Result<(), String> {
+    if admins.contains(&Principal::anonymous()) {
+        return Err("The anonymous principal cannot be an admin".to_string());
+    }
+    ADMINS.with_borrow_mut(|a| {
+        let old: Vec<_> = a.iter().map(|(admin, _)| admin).collect();
+        for admin in old {
+            a.remove(&admin);
+        }
+        for admin in admins {
+            a.insert(admin, ());
+        }
+    });
+    Ok(())
+}
+
+/// Stores the admin that was hard-coded before admins were configurable, so
+/// that it keeps its access across the upgrade. Does nothing if admins have
+/// been stored since.
+pub fn migrate_legacy_admin() {
+    ADMINS.with_borrow_mut(|a| {
+        if a.is_empty() {
+            let admin = Principal::from_text(LEGACY_ADMIN).expect("invalid legacy admin principal");
+            a.insert(admin, ());
+        }
+    });
+}
+
+pub fn add_admin(admin: Principal) -> Result<(), String> {
+    if admin == Principal::anonymous() {
+        return Err("The anonymous principal cannot be an admin".to_string());
+    }
+    ADMINS.with_borrow_mut(|a| a.insert(admin, ()));
+    Ok(())
+}
+
+pub fn remove_admin(admin: &Principal) -> Result<(), String> {
+    ADMINS
+        .with_borrow_mut(|a| a.remove(admin))
+        .map(|_| ())
+        .ok_or_else(|| format!("{} is not an admin", admin))
+}
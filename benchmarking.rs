@@ -1,6 +1,7 @@
 // The code below is used for testing and benchmarking.
 
-use crate::{onnx, Detection, Error, Recognition};
+use crate::auth::{self, Role};
+use crate::{config, gallery, onnx, quantization, CanisterResponse, Detection, Error, Recognition};
 
 const IMAGE: &'static [u8] = include_bytes!("../assets/image.png");
 
@@ -18,11 +19,9 @@ fn fmt(n: u64) -> String {
 
 #[ic_cdk::query]
 fn run_detection() -> Detection {
-    let result = match onnx::detect(IMAGE.to_vec()) {
+    let result = match onnx::detect(IMAGE.to_vec(), &config::get()) {
         Ok(result) => Detection::Ok(result.0),
-        Err(err) => Detection::Err(Error {
-            message: err.to_string(),
-        }),
+        Err(err) => Detection::Err(Error::from(err)),
     };
     let instructions = ic_cdk::api::performance_counter(0);
     ic_cdk::println!("Executed instructions: {}", fmt(instructions));
@@ -31,13 +30,59 @@ fn run_detection() -> Detection {
 
 #[ic_cdk::update]
 fn run_recognition() -> Recognition {
-    let result = match onnx::recognize(IMAGE.to_vec()) {
+    let config = config::get();
+    let result = match onnx::prepare(IMAGE.to_vec(), &config, onnx::Purpose::Recognition)
+        .and_then(|face| gallery::recognize(face, &config))
+    {
         Ok(result) => Recognition::Ok(result),
-        Err(err) => Recognition::Err(Error {
-            message: err.to_string(),
-        }),
+        Err(err) => Recognition::Err(Error::from(err)),
     };
     let instructions = ic_cdk::api::performance_counter(0);
     ic_cdk::println!("Executed instructions: {}", fmt(instructions));
     result
 }
+
+// Every recall sample is compared against the whole gallery.
+const MAX_RECALL_SAMPLES: u32 = 100;
+const MAX_RECALL_K: u32 = 100;
+
+/// Returns the fraction of the `k` closest templates that approximate search
+/// finds, using the first `samples` templates of the gallery as queries.
+#[ic_cdk::query]
+fn run_ann_recall(samples: u32, k: u32) -> CanisterResponse<f32> {
+    if let Err(e) = auth::require_role(Role::Admin) {
+        return CanisterResponse::Err(e);
+    }
+    let recall = gallery::ann_recall(
+        samples.min(MAX_RECALL_SAMPLES) as usize,
+        k.min(MAX_RECALL_K) as usize,
+        &config::get(),
+    );
+    let instructions = ic_cdk::api::performance_counter(0);
+    ic_cdk::println!("Recall: {:.3}", recall);
+    ic_cdk::println!("Executed instructions: {}", fmt(instructions));
+    CanisterResponse::Ok(recall)
+}
+
+// Every pair of samples is compared.
+const MAX_QUANTIZATION_SAMPLES: u32 = 200;
+
+/// Compares the distances between the first `samples` gallery templates stored
+/// with the given precision against full precision.
+#[ic_cdk::query]
+fn run_quantization_benchmark(
+    samples: u32,
+    precision: config::EmbeddingPrecision,
+) -> CanisterResponse<quantization::Report> {
+    if let Err(e) = auth::require_role(Role::Admin) {
+        return CanisterResponse::Err(e);
+    }
+    let report = gallery::quantization_report(
+        samples.min(MAX_QUANTIZATION_SAMPLES) as usize,
+        precision,
+        &config::get(),
+    );
+    let instructions = ic_cdk::api::performance_counter(0);
+    ic_cdk::println!("Executed instructions: {}", fmt(instructions));
+    CanisterResponse::Ok(report)
+}
@@ -0,0 +1,209 @@
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+thread_local! {
+    static CAMPAIGNS: RefCell<StableBTreeMap<u64, Campaign, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::CAMPAIGNS_MEMORY_ID)));
+    // The id of the next campaign, so that the enrollments of a deleted
+    // campaign are not attributed to a new one.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(crate::memory(crate::CAMPAIGNS_NEXT_ID_MEMORY_ID), 0)
+            .expect("failed to initialize the next campaign id"),
+    );
+    // The campaign each enrolled principal was enrolled in.
+    static ENROLLMENTS: RefCell<StableBTreeMap<Principal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::CAMPAIGN_ENROLLMENTS_MEMORY_ID)));
+}
+
+/// A time window during which users may enroll, up to `cap` of them.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Campaign {
+    pub id: u64,
+    pub name: String,
+    // Nanoseconds since the epoch, inclusive.
+    pub start: u64,
+    // Nanoseconds since the epoch, exclusive.
+    pub end: u64,
+    pub cap: u64,
+    pub enrolled: u64,
+}
+
+impl Storable for Campaign {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CampaignArgs {
+    pub name: String,
+    pub start: u64,
+    pub end: u64,
+    pub cap: u64,
+}
+
+/// Stores a new campaign. Campaigns may not overlap so that every enrollment
+/// falls into exactly one of them.
+pub fn create(args: CampaignArgs, now: u64) -> Result<Campaign, String> {
+    if args.start >= args.end {
+        return Err("A campaign must start before it ends".to_string());
+    }
+    if args.end <= now {
+        return Err("A campaign must end in the future".to_string());
+    }
+    if args.cap == 0 {
+        return Err("A campaign must allow at least one enrollment".to_string());
+    }
+    CAMPAIGNS.with_borrow_mut(|campaigns| {
+        if let Some((_, other)) = campaigns
+            .iter()
+            .find(|(_, c)| c.start < args.end && args.start < c.end)
+        {
+            return Err(format!("The campaign overlaps with campaign {}", other.id));
+        }
+        let id = NEXT_ID.with_borrow_mut(crate::next_id);
+        let campaign = Campaign {
+            id,
+            name: args.name,
+            start: args.start,
+            end: args.end,
+            cap: args.cap,
+            enrolled: 0,
+        };
+        campaigns.insert(id, campaign.clone());
+        Ok(campaign)
+    })
+}
+
+/// Deletes a campaign nobody has enrolled in yet. Campaigns with enrollments
+/// are kept so that the enrollments stay attributed to them.
+pub fn delete(id: u64) -> Result<(), String> {
+    CAMPAIGNS.with_borrow_mut(|campaigns| {
+        let campaign = campaigns
+            .get(&id)
+            .ok_or_else(|| format!("Campaign {} does not exist", id))?;
+        if campaign.enrolled > 0 {
+            return Err(format!(
+                "Campaign {} has {} enrollments and cannot be deleted",
+                id, campaign.enrolled
+            ));
+        }
+        campaigns.remove(&id);
+        Ok(())
+    })
+}
+
+/// Returns the campaigns that are running or have not started yet.
+pub fn current_and_upcoming(now: u64) -> Vec<Campaign> {
+    CAMPAIGNS.with_borrow(|campaigns| {
+        campaigns
+            .iter()
+            .map(|(_, c)| c)
+            .filter(|c| c.end > now)
+            .collect()
+    })
+}
+
+/// Returns the campaign running at the given time, if any.
+pub fn active(now: u64) -> Option<Campaign> {
+    CAMPAIGNS.with_borrow(|campaigns| {
+        campaigns
+            .iter()
+            .map(|(_, c)| c)
+            .find(|c| c.start <= now && now < c.end)
+    })
+}
+
+/// Attributes the enrollment of the given principal to the given campaign.
+pub fn record_enrollment(id: u64, principal: Principal) {
+    CAMPAIGNS.with_borrow_mut(|campaigns| {
+        if let Some(mut campaign) = campaigns.get(&id) {
+            campaign.enrolled += 1;
+            campaigns.insert(id, campaign);
+        }
+    });
+    ENROLLMENTS.with_borrow_mut(|enrollments| enrollments.insert(principal, id));
+}
+
+/// Returns the campaign the given principal was enrolled in, if any.
+pub fn enrollment_of(principal: &Principal) -> Option<u64> {
+    ENROLLMENTS.with_borrow(|enrollments| enrollments.get(principal))
+}
+
+/// Forgets which campaign the given principal was enrolled in. The enrollment
+/// still counts towards the campaign's cap.
+pub fn forget(principal: &Principal) {
+    ENROLLMENTS.with_borrow_mut(|enrollments| enrollments.remove(principal));
+}
+
+/// Returns the principals enrolled during the given campaign.
+pub fn enrollments(id: u64) -> Vec<Principal> {
+    ENROLLMENTS.with_borrow(|enrollments| {
+        enrollments
+            .iter()
+            .filter(|(_, campaign)| *campaign == id)
+            .map(|(principal, _)| principal)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(start: u64, end: u64) -> CampaignArgs {
+        CampaignArgs {
+            name: "Campaign".to_string(),
+            start,
+            end,
+            cap: 10,
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_campaigns() {
+        let first = create(args(100, 200), 0).unwrap();
+        for (start, end) in [(150, 250), (50, 150), (120, 180), (50, 250)] {
+            assert_eq!(
+                create(args(start, end), 0).err(),
+                Some(format!("The campaign overlaps with campaign {}", first.id))
+            );
+        }
+    }
+
+    #[test]
+    fn allows_adjacent_campaigns() {
+        // The end of a campaign is exclusive.
+        create(args(100, 200), 0).unwrap();
+        create(args(200, 300), 0).unwrap();
+        create(args(0, 100), 0).unwrap();
+        assert_eq!(current_and_upcoming(0).len(), 3);
+        assert_eq!(active(200).map(|campaign| campaign.start), Some(200));
+    }
+
+    #[test]
+    fn does_not_reuse_ids() {
+        let first = create(args(100, 200), 0).unwrap();
+        delete(first.id).unwrap();
+        let second = create(args(100, 200), 0).unwrap();
+        assert!(second.id > first.id);
+    }
+
+    #[test]
+    fn keeps_campaigns_with_enrollments() {
+        let campaign = create(args(100, 200), 0).unwrap();
+        record_enrollment(campaign.id, Principal::from_slice(&[1]));
+        assert!(delete(campaign.id).is_err());
+        assert_eq!(enrollments(campaign.id).len(), 1);
+        assert!(delete(campaign.id + 1).is_err());
+    }
+}
@@ -0,0 +1,388 @@
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+thread_local! {
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(crate::memory(crate::CONFIG_MEMORY_ID), Config::default())
+            .expect("failed to initialize the config"),
+    );
+    static HISTORY: RefCell<StableBTreeMap<u64, ConfigChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::CONFIG_HISTORY_MEMORY_ID)));
+}
+
+/// How embeddings are compared. Embeddings are L2-normalized, so the cosine
+/// similarity and the dot product only differ for embeddings enrolled with
+/// other models.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DistanceMetric {
+    // The threshold is the maximum Euclidean distance.
+    Euclidean,
+    // The threshold is the minimum cosine similarity.
+    Cosine,
+    // The threshold is the minimum dot product.
+    DotProduct,
+}
+
+/// How the distances to the templates of a person are combined into one
+/// score.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Aggregation {
+    // The distance to the closest template.
+    Min,
+    // The mean distance to all templates.
+    Mean,
+    // The distance to the mean of all templates.
+    Centroid,
+}
+
+/// How gallery templates are stored. Reduced precisions fit more templates
+/// into a canister at some cost in accuracy, see `run_quantization_benchmark`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EmbeddingPrecision {
+    F32,
+    F16,
+    // Eight bits per value with a scale and offset per template.
+    Int8,
+}
+
+/// How identification searches the gallery.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchMode {
+    // Compare against every template.
+    Exact,
+    // Only compare against the templates the index finds. Faster for large
+    // galleries, but may miss the closest person.
+    Approximate,
+}
+
+/// The runtime settings of the canister.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Config {
+    // The maximum number of people in the gallery across all campaigns.
+    pub max_enrollments: Option<u64>,
+    // The number of recognition attempts each user gets.
+    pub max_attempts: u32,
+    // The maximum distance or minimum similarity between face embeddings of
+    // the same person, depending on the metric.
+    pub threshold: f32,
+    pub metric: DistanceMetric,
+    // Detections with a lower confidence are ignored.
+    pub min_face_confidence: f32,
+    pub max_image_bytes: u64,
+    // The maximum width and height of an uploaded image in pixels.
+    pub max_image_dimension: u32,
+    // How much to grow a detected face box on every side before cropping,
+    // relative to the box size. Defaults to `DEFAULT_FACE_MARGIN`. Changing it
+    // requires all faces to be enrolled again.
+    pub face_margin: Option<f32>,
+    // Overlapping detections with a higher intersection over union are
+    // merged into the most confident one. Defaults to `DEFAULT_NMS_IOU_THRESHOLD`.
+    pub nms_iou_threshold: Option<f32>,
+    // The minimum fraction of the image area an enrolled face must cover.
+    // Defaults to `DEFAULT_MIN_FACE_FRACTION`.
+    pub min_face_fraction: Option<f32>,
+    // Quality thresholds, see `quality::Quality`. Unset thresholds are not
+    // enforced.
+    pub min_sharpness: Option<f32>,
+    pub min_brightness: Option<f32>,
+    pub max_brightness: Option<f32>,
+    pub min_contrast: Option<f32>,
+    // Recognition fails for faces the anti-spoofing model scores lower. Not
+    // enforced if unset.
+    pub min_liveness: Option<f32>,
+    // Only verifications through a challenge-response liveness session are
+    // accepted, see `start_liveness_session`. Defaults to false.
+    pub require_liveness_session: Option<bool>,
+    // The maximum number of templates per person, at most `MAX_TEMPLATES`.
+    // Defaults to `DEFAULT_MAX_TEMPLATES`.
+    pub max_templates: Option<u32>,
+    // Defaults to `Aggregation::Min`.
+    pub aggregation: Option<Aggregation>,
+    // Identification fails if the second closest person is not at least this
+    // much further away than the closest one. Not enforced if unset.
+    pub min_margin: Option<f32>,
+    // Defaults to `SearchMode::Exact`.
+    pub search_mode: Option<SearchMode>,
+    // The number of candidates approximate search considers. Defaults to
+    // `DEFAULT_ANN_EF_SEARCH`.
+    pub ann_ef_search: Option<u32>,
+    // The precision new templates are stored with. Existing templates are
+    // converted by `requantize_gallery`. Defaults to `EmbeddingPrecision::F32`.
+    pub embedding_precision: Option<EmbeddingPrecision>,
+}
+
+const DEFAULT_FACE_MARGIN: f32 = 0.2;
+const DEFAULT_NMS_IOU_THRESHOLD: f32 = 0.3;
+const DEFAULT_MIN_FACE_FRACTION: f32 = 0.05;
+const DEFAULT_MAX_TEMPLATES: u32 = 5;
+// Approximate search considers all templates of the closest people, so the
+// number of templates per person bounds the work of every search.
+const MAX_TEMPLATES: u32 = 20;
+const DEFAULT_ANN_EF_SEARCH: u32 = 64;
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_enrollments: None,
+            max_attempts: 3,
+            threshold: 0.85,
+            metric: DistanceMetric::Euclidean,
+            min_face_confidence: 0.7,
+            max_image_bytes: 2 * 1024 * 1024,
+            max_image_dimension: 4096,
+            face_margin: None,
+            nms_iou_threshold: None,
+            min_face_fraction: None,
+            min_sharpness: None,
+            min_brightness: None,
+            max_brightness: None,
+            min_contrast: None,
+            min_liveness: None,
+            require_liveness_session: None,
+            max_templates: None,
+            aggregation: None,
+            min_margin: None,
+            search_mode: None,
+            ann_ef_search: None,
+            embedding_precision: None,
+        }
+    }
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Config {
+    pub fn face_margin(&self) -> f32 {
+        self.face_margin.unwrap_or(DEFAULT_FACE_MARGIN)
+    }
+
+    pub fn nms_iou_threshold(&self) -> f32 {
+        self.nms_iou_threshold.unwrap_or(DEFAULT_NMS_IOU_THRESHOLD)
+    }
+
+    pub fn min_face_fraction(&self) -> f32 {
+        self.min_face_fraction.unwrap_or(DEFAULT_MIN_FACE_FRACTION)
+    }
+
+    pub fn require_liveness_session(&self) -> bool {
+        self.require_liveness_session.unwrap_or(false)
+    }
+
+    pub fn max_templates(&self) -> u32 {
+        self.max_templates.unwrap_or(DEFAULT_MAX_TEMPLATES)
+    }
+
+    pub fn aggregation(&self) -> Aggregation {
+        self.aggregation.unwrap_or(Aggregation::Min)
+    }
+
+    pub fn search_mode(&self) -> SearchMode {
+        self.search_mode.unwrap_or(SearchMode::Exact)
+    }
+
+    pub fn ann_ef_search(&self) -> u32 {
+        self.ann_ef_search.unwrap_or(DEFAULT_ANN_EF_SEARCH)
+    }
+
+    pub fn embedding_precision(&self) -> EmbeddingPrecision {
+        self.embedding_precision.unwrap_or(EmbeddingPrecision::F32)
+    }
+
+    /// Returns the threshold as a maximum distance as computed by
+    /// `Embedding::distance`, which is lower for closer faces for all metrics.
+    pub fn max_distance(&self) -> f32 {
+        match self.metric {
+            DistanceMetric::Euclidean => self.threshold,
+            DistanceMetric::Cosine => 1.0 - self.threshold,
+            DistanceMetric::DotProduct => -self.threshold,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_enrollments == Some(0) {
+            return Err("max_enrollments must be positive".to_string());
+        }
+        if self.max_attempts == 0 {
+            return Err("max_attempts must be positive".to_string());
+        }
+        if !self.threshold.is_finite() {
+            return Err("threshold must be a number".to_string());
+        }
+        match self.metric {
+            DistanceMetric::Euclidean if self.threshold <= 0.0 || self.threshold > 2.0 => {
+                return Err(
+                    "threshold must be between 0 and 2 for the Euclidean metric".to_string()
+                );
+            }
+            DistanceMetric::Cosine | DistanceMetric::DotProduct
+                if !(-1.0..=1.0).contains(&self.threshold) =>
+            {
+                return Err(format!(
+                    "threshold must be between -1 and 1 for the {:?} metric",
+                    self.metric
+                ));
+            }
+            _ => {}
+        }
+        if !(0.0..=1.0).contains(&self.min_face_confidence) {
+            return Err("min_face_confidence must be between 0 and 1".to_string());
+        }
+        if self.max_image_bytes == 0 {
+            return Err("max_image_bytes must be positive".to_string());
+        }
+        if self.max_image_dimension == 0 {
+            return Err("max_image_dimension must be positive".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.face_margin()) {
+            return Err("face_margin must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.nms_iou_threshold()) {
+            return Err("nms_iou_threshold must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.min_face_fraction()) {
+            return Err("min_face_fraction must be between 0 and 1".to_string());
+        }
+        if self
+            .min_sharpness
+            .is_some_and(|min| !min.is_finite() || min < 0.0)
+        {
+            return Err("min_sharpness must not be negative".to_string());
+        }
+        for (name, value) in [
+            ("min_brightness", self.min_brightness),
+            ("max_brightness", self.max_brightness),
+            ("min_contrast", self.min_contrast),
+            ("min_liveness", self.min_liveness),
+        ] {
+            if value.is_some_and(|value| !(0.0..=1.0).contains(&value)) {
+                return Err(format!("{} must be between 0 and 1", name));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_brightness, self.max_brightness) {
+            if min > max {
+                return Err("min_brightness must not exceed max_brightness".to_string());
+            }
+        }
+        if self
+            .min_margin
+            .is_some_and(|min| !min.is_finite() || min < 0.0)
+        {
+            return Err("min_margin must not be negative".to_string());
+        }
+        if self.ann_ef_search() == 0 {
+            return Err("ann_ef_search must be positive".to_string());
+        }
+        if !(1..=MAX_TEMPLATES).contains(&self.max_templates()) {
+            return Err(format!(
+                "max_templates must be between 1 and {}",
+                MAX_TEMPLATES
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A record of who changed the config and when.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ConfigChange {
+    pub config: Config,
+    pub changed_by: Principal,
+    pub changed_at: u64,
+}
+
+impl Storable for ConfigChange {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn get() -> Config {
+    CONFIG.with_borrow(|c| c.get().clone())
+}
+
+/// Validates and stores the given config, recording the change.
+pub fn set(config: Config, changed_by: Principal, changed_at: u64) -> Result<(), String> {
+    config.validate()?;
+    CONFIG
+        .with_borrow_mut(|c| c.set(config.clone()))
+        .map_err(|_| "Failed to store the config".to_string())?;
+    HISTORY.with_borrow_mut(|h| {
+        let id = h.last_key_value().map_or(0, |(id, _)| id + 1);
+        h.insert(
+            id,
+            ConfigChange {
+                config,
+                changed_by,
+                changed_at,
+            },
+        );
+    });
+    Ok(())
+}
+
+pub fn history() -> Vec<ConfigChange> {
+    HISTORY.with_borrow(|h| h.iter().map(|(_, change)| change).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(metric: DistanceMetric, threshold: f32) -> Config {
+        Config {
+            metric,
+            threshold,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn turns_the_threshold_into_a_distance() {
+        assert_eq!(config(DistanceMetric::Euclidean, 0.8).max_distance(), 0.8);
+        assert_eq!(config(DistanceMetric::Cosine, 0.25).max_distance(), 0.75);
+        assert_eq!(config(DistanceMetric::DotProduct, 0.5).max_distance(), -0.5);
+    }
+
+    #[test]
+    fn validates_the_threshold_for_the_metric() {
+        assert!(Config::default().validate().is_ok());
+        assert!(config(DistanceMetric::Euclidean, 0.0).validate().is_err());
+        assert!(config(DistanceMetric::Euclidean, 2.0).validate().is_ok());
+        assert!(config(DistanceMetric::Euclidean, 2.5).validate().is_err());
+        assert!(config(DistanceMetric::Cosine, -1.0).validate().is_ok());
+        assert!(config(DistanceMetric::Cosine, 1.5).validate().is_err());
+        assert!(config(DistanceMetric::DotProduct, f32::NAN)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn bounds_the_templates_per_person() {
+        let config = |max_templates| Config {
+            max_templates: Some(max_templates),
+            ..Config::default()
+        };
+        assert!(config(0).validate().is_err());
+        assert!(config(MAX_TEMPLATES).validate().is_ok());
+        assert!(config(MAX_TEMPLATES + 1).validate().is_err());
+    }
+}
@@ -0,0 +1,1008 @@
+use crate::config::{Aggregation, Config, DistanceMetric, EmbeddingPrecision, SearchMode};
+use crate::hnsw;
+use crate::onnx::{
+    self, analyze, embed_face, DetectedFace, Embedding, ModelsNotLoaded, PreparedFace,
+    Preprocessing,
+};
+use crate::people::{self, PersonId, PersonRecord};
+use crate::quantization;
+use crate::Memory;
+use anyhow::anyhow;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+thread_local! {
+    // The gallery of enrolled faces lives in stable memory so that it
+    // survives canister upgrades.
+    static DB: RefCell<StableBTreeMap<u64, Face, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::GALLERY_MEMORY_ID)));
+    // The id of the next template. Template ids are handed out to users, so
+    // the ids of removed templates must not be given to new ones.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(crate::memory(crate::GALLERY_NEXT_ID_MEMORY_ID), 0)
+            .expect("failed to initialize the next template id"),
+    );
+    // The ids of the templates enrolled by each principal, so that finding
+    // the templates of a principal does not decode the whole gallery.
+    static BY_PRINCIPAL: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(crate::memory(crate::TEMPLATES_BY_PRINCIPAL_MEMORY_ID)),
+    );
+    // The ids of the templates of each person, so that listing people does
+    // not decode the whole gallery.
+    static BY_PERSON: RefCell<StableBTreeMap<(PersonId, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(crate::memory(crate::TEMPLATES_BY_PERSON_MEMORY_ID)),
+    );
+}
+
+/// A gallery entry: the name of a person and the embedding of one image of
+/// their face. A person may have several entries, called templates, which
+/// share the label and the principal.
+#[derive(CandidType, Deserialize, Clone)]
+struct Face {
+    // The label at enrollment. The current label is kept in the person
+    // record.
+    label: String,
+    // The principal that enrolled the face.
+    principal: Principal,
+    embedding: Embedding,
+    // The metric the gallery used when the face was enrolled.
+    metric: DistanceMetric,
+    person: PersonId,
+    // How the embedding was computed.
+    preprocessing: Preprocessing,
+}
+
+impl Storable for Face {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Person {
+    pub id: PersonId,
+    pub label: String,
+    // The distance to the person, see `Embedding::distance`.
+    pub score: f32,
+    // How much further away the second closest person is. Not set if the
+    // gallery holds a single person.
+    pub margin: Option<f32>,
+    // The face the embedding was computed from.
+    pub face: DetectedFace,
+}
+
+/// One of the people closest to a face.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Candidate {
+    pub id: PersonId,
+    pub label: String,
+    // The distance to the person, see `Embedding::distance`.
+    pub score: f32,
+}
+
+/// The people closest to a face, closest first.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Ranking {
+    pub candidates: Vec<Candidate>,
+    // The face the embedding was computed from.
+    pub face: DetectedFace,
+}
+
+/// What the gallery holds about a person, without the embeddings.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PersonInfo {
+    pub record: PersonRecord,
+    pub templates: Vec<u64>,
+}
+
+/// One page of the people in the gallery, ordered by id.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PeoplePage {
+    pub people: Vec<PersonInfo>,
+    // The number of people in the gallery.
+    pub total: u64,
+}
+
+/// The outcome of comparing a face against the faces of one principal.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Match {
+    pub id: PersonId,
+    pub label: String,
+    pub matched: bool,
+    pub distance: f32,
+    pub face: DetectedFace,
+}
+
+/// A stored embedding and the face it was computed from.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Template {
+    pub id: u64,
+    pub embedding: Embedding,
+    pub face: DetectedFace,
+}
+
+/// The person and the first template stored by `add`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Enrollment {
+    pub person: PersonRecord,
+    pub template: Template,
+}
+
+/// The error returned when the gallery was enrolled with another metric than
+/// the configured one.
+#[derive(Debug)]
+pub struct MetricMismatch {
+    pub stored: Option<DistanceMetric>,
+    pub configured: DistanceMetric,
+}
+
+impl std::fmt::Display for MetricMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The gallery was enrolled with the {:?} metric, but {:?} is configured",
+            self.stored, self.configured
+        )
+    }
+}
+
+impl std::error::Error for MetricMismatch {}
+
+/// The error returned when the two closest people are too close to each other
+/// to tell them apart.
+#[derive(Debug)]
+pub struct AmbiguousMatch {
+    pub margin: f32,
+    pub min_margin: f32,
+}
+
+impl std::fmt::Display for AmbiguousMatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The match is ambiguous: the margin to the second closest person is {:.3}, at least {:.3} is required",
+            self.margin, self.min_margin
+        )
+    }
+}
+
+impl std::error::Error for AmbiguousMatch {}
+
+/// The error returned when the images of one person are too far apart to show
+/// the same face.
+#[derive(Debug)]
+pub struct InconsistentTemplates(pub f32);
+
+impl std::fmt::Display for InconsistentTemplates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The images do not appear to show the same person (distance {:.3})",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InconsistentTemplates {}
+
+/// The error returned when a face was enrolled with another preprocessing, see
+/// `onnx::Preprocessing`. The person is replaced
+/// when their principal enrolls again with `add`.
+#[derive(Debug)]
+pub struct OutdatedTemplates;
+
+impl std::fmt::Display for OutdatedTemplates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The face was enrolled with different image processing and must be enrolled again"
+        )
+    }
+}
+
+impl std::error::Error for OutdatedTemplates {}
+
+/// Returns the mean of the given embeddings scaled to unit length, so that
+/// distances to it are on the same scale as distances to a single template.
+fn centroid(templates: &[Embedding]) -> Embedding {
+    let mut v0 = vec![0.0; templates.first().map_or(0, |t| t.values().len())];
+    for template in templates {
+        for (sum, value) in v0.iter_mut().zip(template.values().iter()) {
+            *sum += value;
+        }
+    }
+    for sum in v0.iter_mut() {
+        *sum /= templates.len() as f32;
+    }
+    Embedding::new(v0).normalize()
+}
+
+/// Combines the distances between the given embedding and the templates of one
+/// person into a single score, which is compared against the threshold.
+fn score(templates: &[Embedding], emb: &Embedding, config: &Config) -> f32 {
+    let metric = config.metric;
+    let distances = templates.iter().map(|t| t.distance(emb, metric));
+    match config.aggregation() {
+        Aggregation::Min => distances.fold(f32::INFINITY, f32::min),
+        Aggregation::Mean => distances.sum::<f32>() / templates.len() as f32,
+        Aggregation::Centroid => centroid(templates).distance(emb, metric),
+    }
+}
+
+/// Fails if the given face was enrolled with another metric than the
+/// configured one, because its distances would not be comparable.
+fn check_metric(face: &Face, config: &Config) -> Result<(), MetricMismatch> {
+    if face.metric != config.metric {
+        return Err(MetricMismatch {
+            stored: Some(face.metric),
+            configured: config.metric,
+        });
+    }
+    Ok(())
+}
+
+/// Fails if the given face was enrolled with another preprocessing, because
+/// new embeddings would only appear far away from it.
+fn check_preprocessing(face: &Face, config: &Config) -> Result<(), OutdatedTemplates> {
+    if face.preprocessing != Preprocessing::current(config) {
+        return Err(OutdatedTemplates);
+    }
+    Ok(())
+}
+
+/// Fails if any template of the given principal was enrolled with another
+/// preprocessing. Checked before a verification attempt is counted.
+pub fn check_up_to_date(principal: Principal, config: &Config) -> Result<(), OutdatedTemplates> {
+    templates_of(principal)
+        .iter()
+        .try_for_each(|(_, face)| check_preprocessing(face, config))
+}
+
+/// Returns the metric the gallery was enrolled with, or `None` if the gallery
+/// is empty.
+pub fn gallery_metric() -> Option<DistanceMetric> {
+    DB.with_borrow(|db| db.first_key_value().map(|(_, face)| face.metric))
+}
+
+/// Adds the given face to the templates of its person. Outdated faces are left
+/// out, see `OutdatedTemplates`: they would never match.
+fn group(
+    people: &mut BTreeMap<PersonId, Vec<Embedding>>,
+    face: Face,
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    check_metric(&face, config)?;
+    if check_preprocessing(&face, config).is_err() {
+        return Ok(());
+    }
+    people.entry(face.person).or_default().push(face.embedding);
+    Ok(())
+}
+
+/// Groups the gallery into people.
+fn people(config: &Config) -> Result<BTreeMap<PersonId, Vec<Embedding>>, anyhow::Error> {
+    DB.with_borrow(|db| {
+        let mut people = BTreeMap::new();
+        for (_, face) in db.iter() {
+            group(&mut people, face, config)?;
+        }
+        Ok(people)
+    })
+}
+
+/// Returns the templates enrolled by the given principal with their ids.
+fn templates_of(principal: Principal) -> Vec<(u64, Face)> {
+    DB.with_borrow(|db| {
+        template_ids(principal)
+            .into_iter()
+            .filter_map(|id| Some((id, db.get(&id)?)))
+            .collect()
+    })
+}
+
+/// Fails unless every new embedding is within the threshold of every other new
+/// embedding and of every existing one.
+fn check_consistency(
+    new: &[Embedding],
+    existing: &[Embedding],
+    config: &Config,
+) -> Result<(), anyhow::Error> {
+    for (i, a) in new.iter().enumerate() {
+        for b in new[i + 1..].iter().chain(existing) {
+            let distance = a.distance(b, config.metric);
+            if distance > config.max_distance() {
+                return Err(InconsistentTemplates(distance).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns every person in the gallery with their distance to the given
+/// embedding, closest first.
+fn rank(emb: &Embedding, k: usize, config: &Config) -> Result<Vec<Candidate>, anyhow::Error> {
+    let people = match config.search_mode() {
+        // The index misses templates while it is being rebuilt.
+        SearchMode::Approximate if hnsw::rebuild_cursor().is_none() => {
+            nearest_people(emb, k, config)?
+        }
+        _ => people(config)?,
+    };
+    let mut candidates: Vec<Candidate> = people
+        .into_iter()
+        .filter_map(|(id, templates)| {
+            Some(Candidate {
+                id,
+                label: people::get(id)?.label,
+                score: score(&templates, emb, config),
+            })
+        })
+        .collect();
+    candidates.sort_by(|a, b| f32::total_cmp(&a.score, &b.score));
+    candidates.truncate(k);
+    Ok(candidates)
+}
+
+/// Returns the distance between the given embedding and the given template.
+fn template_distance(emb: &Embedding, id: u64, metric: DistanceMetric) -> f32 {
+    DB.with_borrow(|db| db.get(&id))
+        .map_or(f32::INFINITY, |face| face.embedding.distance(emb, metric))
+}
+
+/// Like `people`, but only with the people the index finds among the
+/// templates closest to the given embedding. With the mean and centroid
+/// aggregation, people are only scored on the templates that were found.
+fn nearest_people(
+    emb: &Embedding,
+    k: usize,
+    config: &Config,
+) -> Result<BTreeMap<PersonId, Vec<Embedding>>, anyhow::Error> {
+    // Make room for all templates of the k closest people.
+    let ef = (config.ann_ef_search() as usize).max(k * config.max_templates() as usize);
+    let found = hnsw::search(ef, ef, |id| template_distance(emb, id, config.metric));
+    DB.with_borrow(|db| {
+        let mut people = BTreeMap::new();
+        for (id, _) in found {
+            if let Some(face) = db.get(&id) {
+                group(&mut people, face, config)?;
+            }
+        }
+        Ok(people)
+    })
+}
+
+/// Links the given template into the index.
+fn index(id: u64, metric: DistanceMetric) {
+    let emb = match DB.with_borrow(|db| db.get(&id)) {
+        Some(face) => face.embedding,
+        None => return,
+    };
+    hnsw::insert(
+        id,
+        |other| template_distance(&emb, other, metric),
+        |a, b| distance_between(a, b, metric),
+    );
+}
+
+fn distance_between(a: u64, b: u64, metric: DistanceMetric) -> f32 {
+    match DB.with_borrow(|db| db.get(&a)) {
+        Some(face) => template_distance(&face.embedding, b, metric),
+        None => f32::INFINITY,
+    }
+}
+
+/// Converts up to `limit` gallery templates stored with another precision to
+/// the given precision and returns the number of templates left to convert.
+pub fn requantize(precision: EmbeddingPrecision, limit: usize) -> u64 {
+    DB.with_borrow_mut(|db| {
+        let pending: Vec<(u64, Face)> = db
+            .iter()
+            .filter(|(_, face)| face.embedding.precision() != precision)
+            .collect();
+        let left = pending.len().saturating_sub(limit) as u64;
+        for (id, mut face) in pending.into_iter().take(limit) {
+            face.embedding = face.embedding.quantize(precision);
+            db.insert(id, face);
+        }
+        left
+    })
+}
+
+/// Compares the distances between the first `samples` gallery templates stored
+/// with the given precision against full precision. Used to choose
+/// `embedding_precision`.
+pub fn quantization_report(
+    samples: usize,
+    precision: EmbeddingPrecision,
+    config: &Config,
+) -> quantization::Report {
+    let templates: Vec<Embedding> = DB.with_borrow(|db| {
+        db.iter()
+            .take(samples)
+            .map(|(_, face)| face.embedding.quantize(EmbeddingPrecision::F32))
+            .collect()
+    });
+    let quantized: Vec<Embedding> = templates.iter().map(|t| t.quantize(precision)).collect();
+    let size = |embeddings: &[Embedding]| {
+        let total: usize = embeddings.iter().map(|e| e.to_bytes().len()).sum();
+        total.checked_div(embeddings.len()).unwrap_or(0) as u64
+    };
+
+    let (mut total_error, mut max_error, mut pairs, mut agreeing) = (0.0, 0.0f32, 0, 0);
+    for (i, a) in templates.iter().enumerate() {
+        for (b, b_quantized) in templates.iter().zip(quantized.iter()).skip(i + 1) {
+            let exact = a.distance(b, config.metric);
+            let approximate = a.distance(b_quantized, config.metric);
+            let error = (exact - approximate).abs();
+            total_error += error;
+            max_error = max_error.max(error);
+            pairs += 1;
+            if (exact <= config.max_distance()) == (approximate <= config.max_distance()) {
+                agreeing += 1;
+            }
+        }
+    }
+    quantization::Report {
+        precision,
+        f32_bytes: size(&templates),
+        quantized_bytes: size(&quantized),
+        mean_distance_error: if pairs == 0 {
+            0.0
+        } else {
+            total_error / pairs as f32
+        },
+        max_distance_error: max_error,
+        agreement: if pairs == 0 {
+            1.0
+        } else {
+            agreeing as f32 / pairs as f32
+        },
+    }
+}
+
+/// Starts rebuilding the index from all templates in the gallery, see
+/// `continue_index_rebuild`. Identification uses exact search until the
+/// rebuild is complete.
+pub fn start_index_rebuild() {
+    hnsw::start_rebuild();
+}
+
+/// Returns true while the index is being rebuilt.
+pub fn index_rebuilding() -> bool {
+    hnsw::rebuild_cursor().is_some()
+}
+
+/// Links up to `limit` more templates into the index being rebuilt.
+pub fn continue_index_rebuild(metric: DistanceMetric, limit: usize) {
+    let from = match hnsw::rebuild_cursor() {
+        Some(from) => from,
+        None => return,
+    };
+    let ids: Vec<u64> =
+        DB.with_borrow(|db| db.range(from..).take(limit + 1).map(|(id, _)| id).collect());
+    for &id in ids.iter().take(limit) {
+        index(id, metric);
+    }
+    hnsw::set_rebuild_cursor(ids.get(limit).copied());
+}
+
+/// Returns the fraction of the `k` closest templates that the index finds,
+/// using the first `samples` templates of the gallery as queries. Used to
+/// tune `ann_ef_search` against exact search.
+pub fn ann_recall(samples: usize, k: usize, config: &Config) -> f32 {
+    let queries: Vec<Embedding> =
+        DB.with_borrow(|db| db.iter().take(samples).map(|(_, f)| f.embedding).collect());
+    let (mut found, mut total) = (0, 0);
+    for query in &queries {
+        let mut exact: Vec<(u64, f32)> = DB.with_borrow(|db| {
+            db.iter()
+                .map(|(id, face)| (id, face.embedding.distance(query, config.metric)))
+                .collect()
+        });
+        exact.sort_by(|a, b| f32::total_cmp(&a.1, &b.1));
+        exact.truncate(k);
+        let approximate = hnsw::search(k, config.ann_ef_search() as usize, |id| {
+            template_distance(query, id, config.metric)
+        });
+        found += approximate
+            .iter()
+            .filter(|(id, _)| exact.iter().any(|(exact, _)| exact == id))
+            .count();
+        total += exact.len();
+    }
+    if total == 0 {
+        return 1.0;
+    }
+    found as f32 / total as f32
+}
+
+/// Returns the person whose templates are the closest to the face embedding of
+/// the given face. Fails if the closest person is not within the threshold or
+/// if the second closest person is within the configured margin.
+pub fn recognize(face: PreparedFace, config: &Config) -> Result<Person, anyhow::Error> {
+    let (emb, face) = analyze(face, config)?;
+    // The second closest person is needed for the margin.
+    let (best, margin) = identify(rank(&emb, 2, config)?, config)?;
+    Ok(Person {
+        id: best.id,
+        label: best.label,
+        score: best.score,
+        margin,
+        face,
+    })
+}
+
+/// Returns the first of the given candidates, which are ordered closest first,
+/// and how much further away the second one is. Fails under the same
+/// conditions as `recognize`.
+fn identify(
+    candidates: Vec<Candidate>,
+    config: &Config,
+) -> Result<(Candidate, Option<f32>), anyhow::Error> {
+    let mut candidates = candidates.into_iter();
+    let best = candidates.next().ok_or(anyhow!("Unknown person"))?;
+    if best.score > config.max_distance() {
+        return Err(anyhow!("Unknown person"));
+    }
+    let margin = candidates.next().map(|second| second.score - best.score);
+    if let (Some(margin), Some(min_margin)) = (margin, config.min_margin) {
+        if margin < min_margin {
+            return Err(AmbiguousMatch { margin, min_margin }.into());
+        }
+    }
+    Ok((best, margin))
+}
+
+/// Returns up to `k` people closest to the given face, closest first, whether
+/// or not they are within the threshold.
+pub fn recognize_top_k(
+    face: PreparedFace,
+    k: usize,
+    config: &Config,
+) -> Result<Ranking, anyhow::Error> {
+    let (emb, face) = analyze(face, config)?;
+    let candidates = rank(&emb, k, config)?;
+    Ok(Ranking { candidates, face })
+}
+
+/// Compares the given face only against the templates enrolled by the given
+/// principal.
+pub fn verify(
+    principal: Principal,
+    face: PreparedFace,
+    config: &Config,
+) -> Result<Match, anyhow::Error> {
+    let (emb, face) = analyze(face, config)?;
+    verify_embedding(principal, &emb, face, config)
+}
+
+/// Like `verify`, for an embedding computed by `analyze`.
+pub fn verify_embedding(
+    principal: Principal,
+    emb: &Embedding,
+    face: DetectedFace,
+    config: &Config,
+) -> Result<Match, anyhow::Error> {
+    let templates = templates_of(principal);
+    let person = enrolled_person(&templates)?;
+    for (_, face) in &templates {
+        check_metric(face, config)?;
+        check_preprocessing(face, config)?;
+    }
+    let templates: Vec<Embedding> = templates.into_iter().map(|(_, f)| f.embedding).collect();
+    let distance = score(&templates, emb, config);
+    Ok(Match {
+        id: person.id,
+        label: person.label,
+        matched: distance <= config.max_distance(),
+        distance,
+        face,
+    })
+}
+
+/// Returns the person the given templates of one principal belong to.
+fn enrolled_person(templates: &[(u64, Face)]) -> Result<PersonRecord, anyhow::Error> {
+    templates
+        .first()
+        .and_then(|(_, face)| people::get(face.person))
+        .ok_or(anyhow!("No face enrolled for the caller"))
+}
+
+/// Returns the number of people in the gallery.
+pub fn gallery_size() -> u64 {
+    people::count()
+}
+
+fn insert(person: &PersonRecord, embedding: Embedding, config: &Config) -> u64 {
+    let id = DB.with_borrow_mut(|db| {
+        let id = NEXT_ID.with_borrow_mut(crate::next_id);
+        db.insert(
+            id,
+            Face {
+                label: person.label.clone(),
+                principal: person.principal,
+                embedding: embedding.quantize(config.embedding_precision()),
+                metric: config.metric,
+                person: person.id,
+                preprocessing: Preprocessing::current(config),
+            },
+        );
+        id
+    });
+    BY_PRINCIPAL.with_borrow_mut(|index| index.insert((person.principal, id), ()));
+    BY_PERSON.with_borrow_mut(|index| index.insert((person.id, id), ()));
+    // A rebuild links the template once it gets to it, because new templates
+    // get higher ids than all existing ones.
+    if hnsw::rebuild_cursor().is_none() {
+        index(id, config.metric);
+    }
+    id
+}
+
+/// Records a new person with the given name and face into the state. The face
+/// should come from `prepare` for enrollment. Further faces are added with
+/// `add_template`. Replaces the people of the principal if their templates
+/// are outdated.
+pub fn add(
+    label: &str,
+    principal: Principal,
+    face: PreparedFace,
+    config: &Config,
+    now: u64,
+) -> Result<Enrollment, anyhow::Error> {
+    let (embedding, face) = embed_face(face)?;
+    if gallery_metric().is_some_and(|metric| metric != config.metric) {
+        return Err(MetricMismatch {
+            stored: gallery_metric(),
+            configured: config.metric,
+        }
+        .into());
+    }
+    let version = onnx::recognition_model_version().ok_or(ModelsNotLoaded)?;
+    if check_up_to_date(principal, config).is_err() {
+        for person in people_of(principal) {
+            delete_person(person.record.id)?;
+        }
+    }
+    let person = people::create(label, principal, version, now).map_err(|e| anyhow!(e))?;
+    let template = Template {
+        id: insert(&person, embedding.clone(), config),
+        embedding,
+        face,
+    };
+    Ok(Enrollment { person, template })
+}
+
+/// Adds a template to the person enrolled by the given principal. The face
+/// should come from `prepare` for enrollment and must match all existing
+/// templates.
+pub fn add_template(
+    principal: Principal,
+    face: PreparedFace,
+    config: &Config,
+    now: u64,
+) -> Result<Template, anyhow::Error> {
+    let existing = templates_of(principal);
+    let person = enrolled_person(&existing)?;
+    if existing.len() >= config.max_templates() as usize {
+        return Err(anyhow!(
+            "At most {} templates are allowed per person",
+            config.max_templates()
+        ));
+    }
+    for (_, face) in &existing {
+        check_metric(face, config)?;
+        check_preprocessing(face, config)?;
+    }
+    let existing: Vec<Embedding> = existing.into_iter().map(|(_, f)| f.embedding).collect();
+    let (embedding, face) = embed_face(face)?;
+    check_consistency(std::slice::from_ref(&embedding), &existing, config)?;
+    let id = insert(&person, embedding.clone(), config);
+    people::touch(person.id, now);
+    Ok(Template {
+        id,
+        embedding,
+        face,
+    })
+}
+
+/// Removes the given template of the person enrolled by the given principal.
+/// The last template of a person cannot be removed.
+pub fn remove_template(principal: Principal, id: u64, now: u64) -> Result<(), anyhow::Error> {
+    let templates = templates_of(principal);
+    let face = templates
+        .iter()
+        .find(|(template, _)| *template == id)
+        .map(|(_, face)| face)
+        .ok_or(anyhow!("Template {} does not exist", id))?;
+    if templates.len() == 1 {
+        return Err(anyhow!("The last template of a person cannot be removed"));
+    }
+    people::touch(face.person, now);
+    unlink(id);
+    Ok(())
+}
+
+/// Removes the given template from the gallery and the index.
+fn unlink(id: u64) {
+    let face = match DB.with_borrow(|db| db.get(&id)) {
+        Some(face) => face,
+        None => return,
+    };
+    hnsw::remove(id, |a, b| distance_between(a, b, face.metric));
+    BY_PRINCIPAL.with_borrow_mut(|index| index.remove(&(face.principal, id)));
+    BY_PERSON.with_borrow_mut(|index| index.remove(&(face.person, id)));
+    DB.with_borrow_mut(|db| db.remove(&id));
+}
+
+fn person_info(record: PersonRecord) -> PersonInfo {
+    let templates = BY_PERSON.with_borrow(|index| {
+        index
+            .range((record.id, 0)..=(record.id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    PersonInfo { record, templates }
+}
+
+/// Returns up to `limit` people, skipping the first `offset`.
+pub fn list_people(offset: usize, limit: usize) -> PeoplePage {
+    PeoplePage {
+        people: people::page(offset, limit)
+            .into_iter()
+            .map(person_info)
+            .collect(),
+        total: people::count(),
+    }
+}
+
+pub fn get_person(id: PersonId) -> Option<PersonInfo> {
+    people::get(id).map(person_info)
+}
+
+fn person_or_error(id: PersonId) -> Result<PersonInfo, anyhow::Error> {
+    get_person(id).ok_or(anyhow!("Person {} does not exist", id))
+}
+
+pub fn relabel(id: PersonId, label: &str, now: u64) -> Result<PersonInfo, anyhow::Error> {
+    people::relabel(id, label, now).map_err(|e| anyhow!(e))?;
+    person_or_error(id)
+}
+
+pub fn set_attributes(
+    id: PersonId,
+    attributes: Vec<(String, String)>,
+    now: u64,
+) -> Result<PersonInfo, anyhow::Error> {
+    people::set_attributes(id, attributes, now).map_err(|e| anyhow!(e))?;
+    person_or_error(id)
+}
+
+/// Removes the given person with all of their templates.
+pub fn delete_person(id: PersonId) -> Result<PersonInfo, anyhow::Error> {
+    let info = person_or_error(id)?;
+    for template in &info.templates {
+        unlink(*template);
+    }
+    people::remove(id);
+    Ok(info)
+}
+
+/// Removes the given template of any person and returns the person with the
+/// templates they have left. Removing the last template removes the person.
+pub fn delete_template(id: u64, now: u64) -> Result<PersonInfo, anyhow::Error> {
+    let face = DB
+        .with_borrow(|db| db.get(&id))
+        .ok_or(anyhow!("Template {} does not exist", id))?;
+    let person = face.person;
+    let mut info = person_or_error(person)?;
+    unlink(id);
+    info.templates.retain(|template| *template != id);
+    if info.templates.is_empty() {
+        people::remove(person);
+    } else {
+        people::touch(person, now);
+        info.record.updated_at = now;
+    }
+    Ok(info)
+}
+
+/// Returns the people enrolled by the given principal.
+pub fn people_of(principal: Principal) -> Vec<PersonInfo> {
+    people::of_principal(&principal)
+        .into_iter()
+        .map(person_info)
+        .collect()
+}
+
+/// Returns the given templates with full precision.
+pub fn embeddings_of(templates: &[u64]) -> Vec<(u64, Embedding)> {
+    DB.with_borrow(|db| {
+        templates
+            .iter()
+            .filter_map(|&id| {
+                let face = db.get(&id)?;
+                Some((id, face.embedding.quantize(EmbeddingPrecision::F32)))
+            })
+            .collect()
+    })
+}
+
+/// Returns the ids of the templates enrolled by the given principal.
+pub fn template_ids(principal: Principal) -> Vec<u64> {
+    BY_PRINCIPAL.with_borrow(|index| {
+        index
+            .range((principal, 0)..=(principal, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedding(values: &[f32]) -> Embedding {
+        Embedding::new(values.to_vec())
+    }
+
+    fn config(aggregation: Aggregation) -> Config {
+        Config {
+            aggregation: Some(aggregation),
+            ..Config::default()
+        }
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn centroid_has_unit_length() {
+        let centroid = centroid(&[embedding(&[2.0, 0.0]), embedding(&[0.0, 2.0])]);
+        let values = centroid.values();
+        assert_close(values[0], 0.5f32.sqrt());
+        assert_close(values[1], 0.5f32.sqrt());
+    }
+
+    #[test]
+    fn aggregates_the_distances_to_the_templates() {
+        let templates = [embedding(&[1.0, 0.0]), embedding(&[0.0, 1.0])];
+        let emb = embedding(&[1.0, 0.0]);
+        assert_close(score(&templates, &emb, &config(Aggregation::Min)), 0.0);
+        assert_close(
+            score(&templates, &emb, &config(Aggregation::Mean)),
+            2.0f32.sqrt() / 2.0,
+        );
+        // The centroid lies at 45 degrees on the unit circle.
+        let centroid = [0.5f32.sqrt(), 0.5f32.sqrt()];
+        let expected = ((1.0 - centroid[0]).powi(2) + centroid[1].powi(2)).sqrt();
+        assert_close(
+            score(&templates, &emb, &config(Aggregation::Centroid)),
+            expected,
+        );
+    }
+
+    fn candidate(id: PersonId, score: f32) -> Candidate {
+        Candidate {
+            id,
+            label: format!("person {}", id),
+            score,
+        }
+    }
+
+    fn enroll(label: &str, values: &[f32], config: &Config) -> PersonId {
+        let person = people::create(label, Principal::anonymous(), "test".to_string(), 0).unwrap();
+        insert(&person, embedding(values), config);
+        person.id
+    }
+
+    #[test]
+    fn identifies_the_closest_person_within_the_threshold() {
+        let config = Config {
+            threshold: 0.5,
+            ..Config::default()
+        };
+        let (best, margin) =
+            identify(vec![candidate(1, 0.25), candidate(2, 0.75)], &config).unwrap();
+        assert_eq!(best.id, 1);
+        assert_eq!(margin, Some(0.5));
+        let (_, margin) = identify(vec![candidate(1, 0.25)], &config).unwrap();
+        assert_eq!(margin, None);
+        assert!(identify(vec![candidate(1, 0.75)], &config).is_err());
+        assert!(identify(vec![], &config).is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_matches() {
+        let config = Config {
+            threshold: 0.5,
+            min_margin: Some(0.25),
+            ..Config::default()
+        };
+        let err = identify(vec![candidate(1, 0.25), candidate(2, 0.375)], &config).unwrap_err();
+        assert!(err.is::<AmbiguousMatch>());
+        assert!(identify(vec![candidate(1, 0.25), candidate(2, 0.5)], &config).is_ok());
+    }
+
+    #[test]
+    fn ranks_the_k_closest_people() {
+        let config = Config::default();
+        let near = enroll("near", &[1.0, 0.0], &config);
+        let far = enroll("far", &[0.0, 1.0], &config);
+        let middle = enroll("middle", &[0.6, 0.8], &config);
+        let emb = embedding(&[1.0, 0.0]);
+        let ranked: Vec<PersonId> = rank(&emb, 2, &config)
+            .unwrap()
+            .iter()
+            .map(|candidate| candidate.id)
+            .collect();
+        assert_eq!(ranked, vec![near, middle]);
+        let ranked = rank(&emb, 5, &config).unwrap();
+        assert_eq!(ranked.len(), 3);
+        assert_eq!(ranked[2].id, far);
+        assert_eq!(ranked[2].label, "far");
+    }
+
+    #[test]
+    fn keeps_the_template_indexes_up_to_date() {
+        let config = Config::default();
+        let principal = Principal::from_slice(&[1]);
+        let person = people::create("alice", principal, "test".to_string(), 0).unwrap();
+        let first = insert(&person, embedding(&[1.0, 0.0]), &config);
+        let second = insert(&person, embedding(&[0.9, 0.1]), &config);
+        let other = enroll("bob", &[0.0, 1.0], &config);
+        assert_eq!(template_ids(principal), vec![first, second]);
+        assert_eq!(
+            get_person(person.id).unwrap().templates,
+            vec![first, second]
+        );
+
+        unlink(first);
+        assert_eq!(template_ids(principal), vec![second]);
+        assert_eq!(get_person(person.id).unwrap().templates, vec![second]);
+
+        delete_person(other).unwrap();
+        assert!(template_ids(Principal::anonymous()).is_empty());
+        assert!(get_person(other).is_none());
+    }
+
+    #[test]
+    fn outdates_templates_when_the_preprocessing_changes() {
+        let config = Config::default();
+        let principal = Principal::from_slice(&[2]);
+        let person = people::create("carol", principal, "test".to_string(), 0).unwrap();
+        insert(&person, embedding(&[1.0, 0.0]), &config);
+        assert!(check_up_to_date(principal, &config).is_ok());
+
+        let config = Config {
+            face_margin: Some(0.3),
+            ..config
+        };
+        assert!(check_up_to_date(principal, &config).is_err());
+        // Outdated templates are left out of identification.
+        assert!(rank(&embedding(&[1.0, 0.0]), 1, &config)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn requires_consistent_templates() {
+        let config = Config {
+            threshold: 0.5,
+            ..Config::default()
+        };
+        let existing = [embedding(&[1.0, 0.0])];
+        assert!(check_consistency(&[embedding(&[0.9, 0.1])], &existing, &config).is_ok());
+        assert!(check_consistency(&[embedding(&[0.0, 1.0])], &existing, &config).is_err());
+    }
+}
@@ -0,0 +1,424 @@
+// A hierarchical navigable small world graph over the gallery templates for
+// approximate nearest neighbour search. The graph only stores template ids:
+// callers pass in the distance functions, which look the embeddings up.
+
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeSet, BinaryHeap};
+
+thread_local! {
+    static NODES: RefCell<StableBTreeMap<u64, Node, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::ANN_NODES_MEMORY_ID)));
+    static ENTRY: RefCell<StableCell<EntryPoint, Memory>> = RefCell::new(
+        StableCell::init(crate::memory(crate::ANN_ENTRY_MEMORY_ID), EntryPoint::default())
+            .expect("failed to initialize the index entry point"),
+    );
+}
+
+// The number of neighbours per node on the upper layers.
+const M: usize = 16;
+// The number of neighbours per node on the bottom layer.
+const M0: usize = 2 * M;
+// The number of candidates considered when linking a new node.
+const EF_CONSTRUCTION: usize = 64;
+
+/// The neighbours of a template on each layer it is part of, bottom first.
+#[derive(CandidType, Deserialize, Clone)]
+struct Node {
+    layers: Vec<Vec<u64>>,
+}
+
+impl Storable for Node {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The node searches start from, which is on the top layer.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct EntryPoint {
+    node: Option<u64>,
+    level: u32,
+    // While the index is being rebuilt, the templates from this id on are not
+    // linked yet.
+    rebuild_from: Option<u64>,
+}
+
+impl Storable for EntryPoint {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A node and its distance to the query, ordered by distance.
+#[derive(Clone, Copy)]
+struct Scored(f32, u64);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        f32::total_cmp(&self.0, &other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Returns the top layer of the given node. The level is derived from the id
+/// so that rebuilding the index gives the same graph.
+fn level(id: u64) -> usize {
+    let hash = Sha256::digest(id.to_le_bytes());
+    let random = u64::from_le_bytes(hash[..8].try_into().unwrap());
+    let uniform = (random as f64 / u64::MAX as f64).max(f64::MIN_POSITIVE);
+    (-uniform.ln() / (M as f64).ln()).floor() as usize
+}
+
+fn max_neighbors(layer: usize) -> usize {
+    if layer == 0 {
+        M0
+    } else {
+        M
+    }
+}
+
+/// Returns up to `ef` nodes of the given layer closest to the query, closest
+/// first, found by a beam search from the given entry points.
+fn search_layer(
+    nodes: &StableBTreeMap<u64, Node, Memory>,
+    entry: &[u64],
+    ef: usize,
+    layer: usize,
+    distance: &impl Fn(u64) -> f32,
+) -> Vec<Scored> {
+    let mut visited: BTreeSet<u64> = entry.iter().copied().collect();
+    let mut candidates: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+    let mut results: BinaryHeap<Scored> = BinaryHeap::new();
+    for &id in entry {
+        let scored = Scored(distance(id), id);
+        candidates.push(Reverse(scored));
+        results.push(scored);
+    }
+
+    while let Some(Reverse(candidate)) = candidates.pop() {
+        if results.len() >= ef && results.peek().is_some_and(|worst| candidate.0 > worst.0) {
+            break;
+        }
+        let neighbors = match nodes.get(&candidate.1) {
+            Some(node) => node.layers.get(layer).cloned().unwrap_or_default(),
+            None => continue,
+        };
+        for neighbor in neighbors {
+            // Links to removed nodes may linger on nodes that were not
+            // linked back from the removed node.
+            if !visited.insert(neighbor) || !nodes.contains_key(&neighbor) {
+                continue;
+            }
+            let scored = Scored(distance(neighbor), neighbor);
+            if results.len() < ef || results.peek().is_some_and(|worst| scored.0 < worst.0) {
+                candidates.push(Reverse(scored));
+                results.push(scored);
+                if results.len() > ef {
+                    results.pop();
+                }
+            }
+        }
+    }
+    results.into_sorted_vec()
+}
+
+/// Keeps the neighbours of the given node on the given layer closest to it.
+fn prune(
+    id: u64,
+    neighbors: &mut Vec<u64>,
+    layer: usize,
+    distance_between: &impl Fn(u64, u64) -> f32,
+) {
+    if neighbors.len() <= max_neighbors(layer) {
+        return;
+    }
+    let mut scored: Vec<Scored> = neighbors
+        .iter()
+        .map(|&n| Scored(distance_between(id, n), n))
+        .collect();
+    scored.sort();
+    *neighbors = scored
+        .into_iter()
+        .take(max_neighbors(layer))
+        .map(|s| s.1)
+        .collect();
+}
+
+/// Links a new template into the graph. `distance` returns the distance of a
+/// template to the new one.
+pub fn insert(id: u64, distance: impl Fn(u64) -> f32, distance_between: impl Fn(u64, u64) -> f32) {
+    let level = level(id);
+    let mut node = Node {
+        layers: vec![vec![]; level + 1],
+    };
+    NODES.with_borrow_mut(|nodes| {
+        ENTRY.with_borrow_mut(|entry_cell| {
+            let entry = entry_cell.get().clone();
+            if let Some(entry_node) = entry.node {
+                let top = entry.level as usize;
+                let mut entry_points = vec![entry_node];
+                for layer in (level + 1..=top).rev() {
+                    entry_points =
+                        vec![search_layer(nodes, &entry_points, 1, layer, &distance)[0].1];
+                }
+                for layer in (0..=level.min(top)).rev() {
+                    let found =
+                        search_layer(nodes, &entry_points, EF_CONSTRUCTION, layer, &distance);
+                    node.layers[layer] = found.iter().take(M).map(|s| s.1).collect();
+                    for &neighbor in &node.layers[layer] {
+                        if let Some(mut other) = nodes.get(&neighbor) {
+                            other.layers[layer].push(id);
+                            prune(neighbor, &mut other.layers[layer], layer, &distance_between);
+                            nodes.insert(neighbor, other);
+                        }
+                    }
+                    entry_points = found.into_iter().map(|s| s.1).collect();
+                }
+            }
+            nodes.insert(id, node);
+            if entry.node.is_none() || level > entry.level as usize {
+                entry_cell
+                    .set(EntryPoint {
+                        node: Some(id),
+                        level: level as u32,
+                        ..entry
+                    })
+                    .expect("failed to update the index entry point");
+            }
+        })
+    });
+}
+
+/// Unlinks a template from the graph, connecting its neighbours with each
+/// other so that the graph stays navigable.
+pub fn remove(id: u64, distance_between: impl Fn(u64, u64) -> f32) {
+    NODES.with_borrow_mut(|nodes| {
+        let node = match nodes.remove(&id) {
+            Some(node) => node,
+            None => return,
+        };
+        for (layer, neighbors) in node.layers.iter().enumerate() {
+            for &neighbor in neighbors {
+                let mut other = match nodes.get(&neighbor) {
+                    Some(other) => other,
+                    None => continue,
+                };
+                let links = &mut other.layers[layer];
+                links.retain(|&link| link != id);
+                for &candidate in neighbors {
+                    if candidate != neighbor && !links.contains(&candidate) {
+                        links.push(candidate);
+                    }
+                }
+                prune(neighbor, links, layer, &distance_between);
+                nodes.insert(neighbor, other);
+            }
+        }
+
+        ENTRY.with_borrow_mut(|entry| {
+            if entry.get().node == Some(id) {
+                let rebuild_from = entry.get().rebuild_from;
+                let next = nodes
+                    .iter()
+                    .max_by_key(|(_, node)| node.layers.len())
+                    .map(|(id, node)| EntryPoint {
+                        node: Some(id),
+                        level: node.layers.len() as u32 - 1,
+                        rebuild_from,
+                    })
+                    .unwrap_or(EntryPoint {
+                        rebuild_from,
+                        ..EntryPoint::default()
+                    });
+                entry
+                    .set(next)
+                    .expect("failed to update the index entry point");
+            }
+        });
+    });
+}
+
+/// Returns up to `k` templates closest to the query with their distances,
+/// closest first. Higher `ef` values find the true nearest templates more
+/// reliably at a higher cost.
+pub fn search(k: usize, ef: usize, distance: impl Fn(u64) -> f32) -> Vec<(u64, f32)> {
+    let entry = ENTRY.with_borrow(|entry| entry.get().clone());
+    let entry_node = match entry.node {
+        Some(node) => node,
+        None => return vec![],
+    };
+    NODES.with_borrow(|nodes| {
+        let mut entry_points = vec![entry_node];
+        for layer in (1..=entry.level as usize).rev() {
+            entry_points = vec![search_layer(nodes, &entry_points, 1, layer, &distance)[0].1];
+        }
+        search_layer(nodes, &entry_points, ef.max(k), 0, &distance)
+            .into_iter()
+            .take(k)
+            .map(|s| (s.1, s.0))
+            .collect()
+    })
+}
+
+/// Removes all templates from the graph.
+pub fn clear() {
+    NODES.with_borrow_mut(|nodes| {
+        let ids: Vec<u64> = nodes.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            nodes.remove(&id);
+        }
+    });
+    ENTRY.with_borrow_mut(|entry| {
+        entry
+            .set(EntryPoint::default())
+            .expect("failed to reset the index entry point")
+    });
+}
+
+/// Removes all templates from the graph and marks the index as being rebuilt.
+/// The caller links the templates back in by id, see `rebuild_cursor`.
+pub fn start_rebuild() {
+    clear();
+    set_rebuild_cursor(Some(0));
+}
+
+/// Returns the id of the first template that is not linked yet while the
+/// index is being rebuilt, or `None` if the index is complete.
+pub fn rebuild_cursor() -> Option<u64> {
+    ENTRY.with_borrow(|entry| entry.get().rebuild_from)
+}
+
+pub fn set_rebuild_cursor(cursor: Option<u64>) {
+    ENTRY.with_borrow_mut(|entry| {
+        let next = EntryPoint {
+            rebuild_from: cursor,
+            ..entry.get().clone()
+        };
+        entry
+            .set(next)
+            .expect("failed to update the index entry point");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMENSIONS: usize = 16;
+
+    /// Returns `count` vectors with values in [-1, 1) from a fixed seed.
+    fn vectors(count: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        let mut next = move || {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+        };
+        (0..count)
+            .map(|_| (0..DIMENSIONS).map(|_| next()).collect())
+            .collect()
+    }
+
+    fn distance(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    /// Returns the fraction of the `k` exact nearest neighbours of the queries
+    /// among the given ids that the index finds.
+    fn recall(gallery: &[Vec<f32>], ids: &[u64], queries: &[Vec<f32>], k: usize) -> f32 {
+        let mut found = 0;
+        for query in queries {
+            let mut exact: Vec<(u64, f32)> = ids
+                .iter()
+                .map(|&id| (id, distance(&gallery[id as usize], query)))
+                .collect();
+            exact.sort_by(|a, b| f32::total_cmp(&a.1, &b.1));
+            exact.truncate(k);
+            let approximate = search(k, 64, |id| distance(&gallery[id as usize], query));
+            assert!(approximate.iter().all(|(id, _)| ids.contains(id)));
+            found += approximate
+                .iter()
+                .filter(|(id, _)| exact.iter().any(|(exact, _)| exact == id))
+                .count();
+        }
+        found as f32 / (queries.len() * k) as f32
+    }
+
+    fn build(gallery: &[Vec<f32>]) {
+        for id in 0..gallery.len() as u64 {
+            insert(
+                id,
+                |other| distance(&gallery[id as usize], &gallery[other as usize]),
+                |a, b| distance(&gallery[a as usize], &gallery[b as usize]),
+            );
+        }
+    }
+
+    #[test]
+    fn finds_nearest_neighbours() {
+        let gallery = vectors(1000, 1);
+        build(&gallery);
+        let ids: Vec<u64> = (0..gallery.len() as u64).collect();
+        let recall = recall(&gallery, &ids, &vectors(50, 2), 10);
+        assert!(recall >= 0.9, "recall {} is below 0.9", recall);
+    }
+
+    #[test]
+    fn stays_navigable_after_removals() {
+        let gallery = vectors(600, 3);
+        build(&gallery);
+        for id in (0..gallery.len() as u64).step_by(2) {
+            remove(id, |a, b| {
+                distance(&gallery[a as usize], &gallery[b as usize])
+            });
+        }
+        let ids: Vec<u64> = (1..gallery.len() as u64).step_by(2).collect();
+        let recall = recall(&gallery, &ids, &vectors(50, 4), 10);
+        assert!(recall >= 0.85, "recall {} is below 0.85", recall);
+    }
+
+    #[test]
+    fn clear_empties_the_index() {
+        let gallery = vectors(50, 5);
+        build(&gallery);
+        clear();
+        assert!(search(10, 64, |id| distance(&gallery[id as usize], &gallery[0])).is_empty());
+    }
+}
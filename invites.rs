@@ -0,0 +1,248 @@
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+thread_local! {
+    // Invites keyed by id. Only the hash of an invite code is stored.
+    static INVITES: RefCell<StableBTreeMap<u64, StoredInvite, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::INVITES_MEMORY_ID)));
+    // The id of the invite with each code hash, so that a code is looked up
+    // without scanning all invites.
+    static CODES: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::INVITE_CODES_MEMORY_ID)));
+    // The id of the next invite, so that a revoked or used up invite's id is
+    // not given to a new one.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(crate::memory(crate::INVITES_NEXT_ID_MEMORY_ID), 0)
+            .expect("failed to initialize the next invite id"),
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct StoredInvite {
+    id: u64,
+    code_hash: Vec<u8>,
+    uses_left: u32,
+    expires_at: Option<u64>,
+    principal: Option<Principal>,
+    created_by: Principal,
+    created_at: u64,
+}
+
+impl Storable for StoredInvite {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// An invite as listed to enrollment managers, without the hash of its code.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Invite {
+    pub id: u64,
+    // The number of enrollments this invite still allows.
+    pub uses_left: u32,
+    // Nanoseconds since the epoch after which the invite is no longer valid.
+    pub expires_at: Option<u64>,
+    // If set, only this principal may use the invite.
+    pub principal: Option<Principal>,
+    pub created_by: Principal,
+    pub created_at: u64,
+}
+
+impl From<StoredInvite> for Invite {
+    fn from(invite: StoredInvite) -> Self {
+        Self {
+            id: invite.id,
+            uses_left: invite.uses_left,
+            expires_at: invite.expires_at,
+            principal: invite.principal,
+            created_by: invite.created_by,
+            created_at: invite.created_at,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InviteArgs {
+    pub uses: u32,
+    pub expires_at: Option<u64>,
+    pub principal: Option<Principal>,
+}
+
+/// A freshly created invite. The code is only ever returned here.
+#[derive(CandidType, Deserialize)]
+pub struct CreatedInvite {
+    pub id: u64,
+    pub code: String,
+}
+
+fn hash(code: &str) -> [u8; 32] {
+    Sha256::digest(code.as_bytes()).into()
+}
+
+/// Removes the code of an invite from the lookup.
+fn forget_code(invite: &StoredInvite) {
+    if let Ok(code_hash) = <[u8; 32]>::try_from(invite.code_hash.as_slice()) {
+        CODES.with_borrow_mut(|codes| codes.remove(&code_hash));
+    }
+}
+
+/// Stores a new invite with a code derived from the given random bytes.
+pub fn create(
+    args: InviteArgs,
+    random: &[u8],
+    created_by: Principal,
+    now: u64,
+) -> Result<CreatedInvite, String> {
+    if args.uses == 0 {
+        return Err("An invite must allow at least one use".to_string());
+    }
+    if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err("The expiry time must be in the future".to_string());
+    }
+    if random.len() < 16 {
+        return Err("Not enough randomness to create an invite code".to_string());
+    }
+    let code: String = random[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    let code_hash = hash(&code);
+    INVITES.with_borrow_mut(|invites| {
+        let id = NEXT_ID.with_borrow_mut(crate::next_id);
+        CODES.with_borrow_mut(|codes| codes.insert(code_hash, id));
+        invites.insert(
+            id,
+            StoredInvite {
+                id,
+                code_hash: code_hash.to_vec(),
+                uses_left: args.uses,
+                expires_at: args.expires_at,
+                principal: args.principal,
+                created_by,
+                created_at: now,
+            },
+        );
+        Ok(CreatedInvite { id, code })
+    })
+}
+
+/// Returns the id of the invite with the given code if the caller may use it
+/// now. The invite is not consumed, see `consume`.
+pub fn check(code: &str, caller: &Principal, now: u64) -> Result<u64, String> {
+    let invite = CODES
+        .with_borrow(|codes| codes.get(&hash(code)))
+        .and_then(|id| INVITES.with_borrow(|invites| invites.get(&id)))
+        .ok_or("Invalid invite code")?;
+    if invite
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
+        return Err("The invite has expired".to_string());
+    }
+    if invite
+        .principal
+        .is_some_and(|principal| principal != *caller)
+    {
+        return Err("The invite was issued to a different principal".to_string());
+    }
+    Ok(invite.id)
+}
+
+/// Uses up one enrollment of the given invite, removing it once exhausted.
+pub fn consume(id: u64) {
+    INVITES.with_borrow_mut(|invites| {
+        if let Some(mut invite) = invites.get(&id) {
+            invite.uses_left -= 1;
+            if invite.uses_left == 0 {
+                invites.remove(&id);
+                forget_code(&invite);
+            } else {
+                invites.insert(id, invite);
+            }
+        }
+    });
+}
+
+pub fn list() -> Vec<Invite> {
+    INVITES.with_borrow(|invites| invites.iter().map(|(_, invite)| invite.into()).collect())
+}
+
+pub fn revoke(id: u64) -> Result<(), String> {
+    INVITES
+        .with_borrow_mut(|invites| invites.remove(&id))
+        .map(|invite| forget_code(&invite))
+        .ok_or_else(|| format!("Invite {} does not exist", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RANDOM: [u8; 16] = [7; 16];
+
+    fn args(uses: u32, expires_at: Option<u64>, principal: Option<Principal>) -> InviteArgs {
+        InviteArgs {
+            uses,
+            expires_at,
+            principal,
+        }
+    }
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn consumes_every_use_then_removes_the_invite() {
+        let invite = create(args(2, None, None), &RANDOM, user(0), 0).unwrap();
+        assert_eq!(check(&invite.code, &user(1), 1), Ok(invite.id));
+        consume(invite.id);
+        assert_eq!(list()[0].uses_left, 1);
+        assert_eq!(check(&invite.code, &user(2), 1), Ok(invite.id));
+        consume(invite.id);
+        assert!(list().is_empty());
+        assert!(check(&invite.code, &user(1), 1).is_err());
+    }
+
+    #[test]
+    fn expires() {
+        let invite = create(args(1, Some(100), None), &RANDOM, user(0), 0).unwrap();
+        assert_eq!(check(&invite.code, &user(1), 99), Ok(invite.id));
+        assert_eq!(
+            check(&invite.code, &user(1), 100),
+            Err("The invite has expired".to_string())
+        );
+        assert!(create(args(1, Some(100), None), &RANDOM, user(0), 100).is_err());
+    }
+
+    #[test]
+    fn is_bound_to_its_principal() {
+        let invite = create(args(1, None, Some(user(1))), &RANDOM, user(0), 0).unwrap();
+        assert_eq!(check(&invite.code, &user(1), 0), Ok(invite.id));
+        assert!(check(&invite.code, &user(2), 0).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_and_revoked_codes() {
+        let invite = create(args(1, None, None), &RANDOM, user(0), 0).unwrap();
+        assert!(check("unknown", &user(1), 0).is_err());
+        revoke(invite.id).unwrap();
+        assert!(check(&invite.code, &user(1), 0).is_err());
+        assert!(revoke(invite.id).is_err());
+    }
+
+    #[test]
+    fn does_not_reuse_ids() {
+        let first = create(args(1, None, None), &RANDOM, user(0), 0).unwrap();
+        revoke(first.id).unwrap();
+        let second = create(args(1, None, None), &[8; 16], user(0), 0).unwrap();
+        assert!(second.id > first.id);
+    }
+}
@@ -1,61 +1,249 @@
-use candid::{CandidType, Deserialize, Principal};
+use auth::Role;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use gallery::{Enrollment, Match, Person};
 use ic_cdk::api;
+use ic_cdk::api::management_canister::main::raw_rand;
 use ic_cdk::caller;
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager},
-    DefaultMemoryImpl,
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
-use onnx::{setup, BoundingBox, Embedding, Person};
+use onnx::{setup, BoundingBox, FaceDetection};
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
+mod audit;
+mod auth;
 mod benchmarking;
+mod campaigns;
+mod config;
+mod gallery;
+mod hnsw;
+mod invites;
+mod liveness;
 mod onnx;
+mod people;
+mod quality;
+mod quantization;
 mod storage;
 
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
 const WASI_MEMORY_ID: MemoryId = MemoryId::new(0);
+const GALLERY_MEMORY_ID: MemoryId = MemoryId::new(1);
+const STATE_VERSION_MEMORY_ID: MemoryId = MemoryId::new(2);
+const RECOGNITION_ATTEMPTS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const RECOGNITION_RESULTS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ADD_CALLERS_MEMORY_ID: MemoryId = MemoryId::new(5);
+// Memory 6 held the number of enrollments, which `max_enrollments` checks
+// against the gallery instead.
+// Memory 7 held the manual enrollment toggle, which campaigns replaced.
+const ADMINS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(9);
+const INVITES_MEMORY_ID: MemoryId = MemoryId::new(10);
+const CAMPAIGNS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const CAMPAIGN_ENROLLMENTS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(13);
+const CONFIG_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(14);
+const ANN_NODES_MEMORY_ID: MemoryId = MemoryId::new(15);
+const ANN_ENTRY_MEMORY_ID: MemoryId = MemoryId::new(16);
+const AUDIT_MEMORY_ID: MemoryId = MemoryId::new(17);
+const PEOPLE_MEMORY_ID: MemoryId = MemoryId::new(18);
+const PEOPLE_NEXT_ID_MEMORY_ID: MemoryId = MemoryId::new(19);
+const GALLERY_NEXT_ID_MEMORY_ID: MemoryId = MemoryId::new(20);
+const INVITES_NEXT_ID_MEMORY_ID: MemoryId = MemoryId::new(21);
+const INVITE_CODES_MEMORY_ID: MemoryId = MemoryId::new(22);
+const CAMPAIGNS_NEXT_ID_MEMORY_ID: MemoryId = MemoryId::new(23);
+const TEMPLATES_BY_PRINCIPAL_MEMORY_ID: MemoryId = MemoryId::new(24);
+const TEMPLATES_BY_PERSON_MEMORY_ID: MemoryId = MemoryId::new(25);
+
+// The version of the stable memory layout. Bump it whenever the layout of the
+// stable structures changes and handle the migration in `migrate_state`.
+const STATE_VERSION: u32 = 1;
 
 const FACE_DETECTION_FILE: &str = "face-detection.onnx";
 const FACE_RECOGNITION_FILE: &str = "face-recognition.onnx";
+const FACE_LANDMARKS_FILE: &str = "face-landmarks.onnx";
+const FACE_ANTISPOOF_FILE: &str = "face-antispoof.onnx";
 
 thread_local! {
-    static RECOGNITION_ATTEMPTS: RefCell<HashMap<Principal, u32>> = RefCell::new(HashMap::new());
-    static RECOGNITION_RESULTS: RefCell<HashMap<Principal, (String, f32)>> = RefCell::new(HashMap::new());
-
-    static ADD_CALLERS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
-    static ADD_COUNT: RefCell<usize> = RefCell::new(0);
-    static IS_ENABLED: RefCell<bool> = RefCell::new(false);
     // The memory manager is used for simulating multiple memories.
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-}
 
-const MAX_ADD_CALLS: usize = 200;
+    static STATE_VERSION_CELL: RefCell<StableCell<u32, Memory>> = RefCell::new(
+        StableCell::init(memory(STATE_VERSION_MEMORY_ID), 0)
+            .expect("failed to initialize the state version"),
+    );
 
-const ADMIN_PRINCIPAL: &str = "4s4hz-og66m-hypzp-uxv6q-addgn-hshem-dnvln-uhy7t-h3hsc-pmajb-mqe";
+    static RECOGNITION_ATTEMPTS: RefCell<StableBTreeMap<Principal, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(RECOGNITION_ATTEMPTS_MEMORY_ID)));
+    static RECOGNITION_RESULTS: RefCell<StableBTreeMap<Principal, RecognitionResult, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(RECOGNITION_RESULTS_MEMORY_ID)));
 
-const MAX_ATTEMPTS: u32 = 3;
+    static ADD_CALLERS: RefCell<StableBTreeMap<Principal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ADD_CALLERS_MEMORY_ID)));
 
-#[derive(CandidType, Deserialize)]
+    // The models live on the heap, so their status is reset on every upgrade.
+    static MODEL_STATUS: RefCell<ModelStatus> = RefCell::new(ModelStatus::NotLoaded);
+    // Whether a timer continuing the index rebuild is pending.
+    static INDEX_REBUILD_SCHEDULED: RefCell<bool> = RefCell::new(false);
+}
+
+/// Returns the virtual memory with the given id.
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Returns the next id of the given counter and advances it, so that ids are
+/// never reused, even after the entry with the highest id is removed.
+fn next_id(counter: &mut StableCell<u64, Memory>) -> u64 {
+    let id = *counter.get();
+    counter
+        .set(id + 1)
+        .expect("failed to update the id counter");
+    id
+}
+
+#[derive(CandidType, Deserialize, Clone)]
 struct RecognitionResult {
     label: String,
     score: f32,
+    person: people::PersonId,
+}
+
+impl Storable for RecognitionResult {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum ModelStatus {
+    NotLoaded,
+    Loading,
+    Ready,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize)]
+enum ErrorKind {
+    // The models have not been set up yet, see `model_status`.
+    ModelsNotLoaded,
+    // The enrollment image contains no face.
+    NoFace,
+    // The enrollment image contains more than one face.
+    MultipleFaces,
+    // The face in the enrollment image is too small.
+    FaceTooSmall,
+    // The image does not pass the quality thresholds, see `assess_quality`.
+    LowQuality,
+    // The anti-spoofing model suspects a photo or a screen.
+    SpoofSuspected,
+    // The two closest people are too close to each other, see `min_margin`.
+    AmbiguousMatch,
+    // The images of one person do not appear to show the same face.
+    InconsistentTemplates,
+    // The face was enrolled with different image processing, for example
+    // another `face_margin`, and must be enrolled again.
+    OutdatedTemplates,
+    Other,
 }
 
 #[derive(CandidType, Deserialize)]
 struct Error {
+    kind: ErrorKind,
     message: String,
 }
 
+impl Error {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            kind: ErrorKind::Other,
+            message: message.into(),
+        }
+    }
+
+    fn models_not_loaded() -> Self {
+        Self {
+            kind: ErrorKind::ModelsNotLoaded,
+            message: onnx::ModelsNotLoaded.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let kind = if err.is::<onnx::ModelsNotLoaded>() {
+            ErrorKind::ModelsNotLoaded
+        } else if err.is::<quality::LowQuality>() {
+            ErrorKind::LowQuality
+        } else if err.is::<onnx::SpoofSuspected>() {
+            ErrorKind::SpoofSuspected
+        } else if err.is::<gallery::AmbiguousMatch>() {
+            ErrorKind::AmbiguousMatch
+        } else if err.is::<gallery::InconsistentTemplates>() {
+            ErrorKind::InconsistentTemplates
+        } else if err.is::<gallery::OutdatedTemplates>() {
+            ErrorKind::OutdatedTemplates
+        } else if let Some(rejection) = err.downcast_ref::<onnx::EnrollmentRejection>() {
+            match rejection {
+                onnx::EnrollmentRejection::NoFace => ErrorKind::NoFace,
+                onnx::EnrollmentRejection::MultipleFaces(_) => ErrorKind::MultipleFaces,
+                onnx::EnrollmentRejection::FaceTooSmall(_, _) => ErrorKind::FaceTooSmall,
+            }
+        } else {
+            ErrorKind::Other
+        };
+        Self {
+            kind,
+            message: err.to_string(),
+        }
+    }
+}
+
 #[derive(CandidType, Deserialize)]
 enum Detection {
     Ok(BoundingBox),
     Err(Error),
 }
 
+#[derive(CandidType, Deserialize)]
+enum FaceDetections {
+    Ok(Vec<FaceDetection>),
+    Err(Error),
+}
+
+#[derive(CandidType, Deserialize)]
+struct QualityReport {
+    quality: quality::Quality,
+    // The configured thresholds the image does not meet.
+    issues: Vec<quality::QualityIssue>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum QualityAssessment {
+    Ok(QualityReport),
+    Err(Error),
+}
+
 #[derive(CandidType, Deserialize)]
 enum Addition {
-    Ok(Embedding),
+    Ok(Enrollment),
+    Err(Error),
+}
+
+#[derive(CandidType, Deserialize)]
+enum TemplateAddition {
+    Ok(gallery::Template),
     Err(Error),
 }
 
@@ -65,202 +253,723 @@ enum Recognition {
     Err(Error),
 }
 
-fn detect(image: Vec<u8>) -> Detection {
-    let result: Detection = match onnx::detect(image) {
-        Ok(result) => Detection::Ok(result.0),
-        Err(err) => Detection::Err(Error {
-            message: err.to_string(),
+#[derive(CandidType, Deserialize)]
+enum RankedRecognition {
+    Ok(gallery::Ranking),
+    Err(Error),
+}
+
+#[derive(CandidType, Deserialize)]
+enum Verification {
+    Ok(Match),
+    Err(Error),
+}
+
+#[derive(CandidType, Deserialize)]
+enum LivenessSession {
+    Ok(liveness::Session),
+    Err(Error),
+}
+
+/// Returns all faces in the given image above the configured confidence, so
+/// that clients can check an image before using it for recognition.
+#[ic_cdk::query]
+fn detect_faces(image: Vec<u8>) -> FaceDetections {
+    let config = config::get();
+    if let Err(err) = onnx::check_image(&image, &config) {
+        return FaceDetections::Err(Error::from(err));
+    }
+    match onnx::detect_faces(image, &config) {
+        Ok(faces) => FaceDetections::Ok(faces),
+        Err(err) => FaceDetections::Err(Error::from(err)),
+    }
+}
+
+/// Checks the size of the given image, finds the face in it and checks the
+/// quality of the face against the configured thresholds. Enrollment images
+/// must also pass the enrollment policy.
+fn prepare(
+    image: Vec<u8>,
+    config: &config::Config,
+    purpose: onnx::Purpose,
+) -> Result<onnx::PreparedFace, Error> {
+    onnx::check_image(&image, config)?;
+    let face = onnx::prepare(image, config, purpose)?;
+    quality::check(&face.quality, config, purpose).map_err(anyhow::Error::new)?;
+    Ok(face)
+}
+
+/// Returns the quality of the face in the given image and the configured
+/// thresholds it does not meet, so that clients can give feedback before
+/// using an image for enrollment or recognition.
+#[ic_cdk::query]
+fn assess_quality(image: Vec<u8>) -> QualityAssessment {
+    let config = config::get();
+    if let Err(err) = onnx::check_image(&image, &config) {
+        return QualityAssessment::Err(Error::from(err));
+    }
+    match onnx::prepare(image, &config, onnx::Purpose::Recognition) {
+        Ok(face) => QualityAssessment::Ok(QualityReport {
+            // The enrollment thresholds are the stricter ones.
+            issues: quality::issues(&face.quality, &config, onnx::Purpose::Enrollment),
+            quality: face.quality,
         }),
-    };
-    result
+        Err(err) => QualityAssessment::Err(Error::from(err)),
+    }
 }
 
-#[ic_cdk::update]
-fn recognize(image: Vec<u8>) -> Recognition {
-    let caller = ic_cdk::caller();
+/// Returns an error unless the given user may still verify their face.
+fn check_can_verify(user: &Principal) -> Result<(), Error> {
+    if !ADD_CALLERS.with(|callers| callers.borrow().contains_key(user)) {
+        return Err(Error::new("Unauthorized: User not in the allowed set"));
+    }
 
-    if !ADD_CALLERS.with(|callers| callers.borrow().contains(&caller)) {
-        return Recognition::Err(Error {
-            message: "Unauthorized: User not in the allowed set".to_string(),
-        });
+    if RECOGNITION_RESULTS.with(|results| results.borrow().contains_key(user)) {
+        return Err(Error::new(
+            "Recognition already successful. Further attempts not allowed",
+        ));
     }
 
-    if RECOGNITION_RESULTS.with(|results| results.borrow().contains_key(&caller)) {
-        return Recognition::Err(Error {
-            message: "Recognition already successful. Further attempts not allowed".to_string(),
-        });
+    // Don't consume an attempt if the canister is not ready yet.
+    if !onnx::models_loaded() {
+        return Err(Error::models_not_loaded());
     }
 
+    // Nor if the user's templates could never match.
+    gallery::check_up_to_date(*user, &config::get()).map_err(anyhow::Error::new)?;
+    Ok(())
+}
+
+/// Counts a verification attempt of the given user and returns the number of
+/// attempts so far, or an error if the user has no attempts left.
+fn consume_attempt(user: Principal, config: &config::Config) -> Result<u32, Error> {
     let attempts = RECOGNITION_ATTEMPTS.with(|attempts| {
         let mut attempts = attempts.borrow_mut();
-        let count = attempts.entry(caller).or_insert(0);
-        *count += 1;
-        *count
+        let count = attempts.get(&user).unwrap_or(0) + 1;
+        attempts.insert(user, count);
+        count
     });
 
-    if attempts > MAX_ATTEMPTS {
-        return Recognition::Err(Error {
-            message: "Maximum recognition attempts exceeded".to_string(),
-        });
+    if attempts > config.max_attempts {
+        return Err(Error::new("Maximum recognition attempts exceeded"));
     }
+    Ok(attempts)
+}
 
-    match onnx::recognize(image) {
-        Ok(person) => {
-            RECOGNITION_RESULTS.with(|results| {
-                results
-                    .borrow_mut()
-                    .insert(caller, (person.label.clone(), person.score));
-            });
-
-            Recognition::Ok(person)
+/// Records the given verification of the user on a match. Errors on the last
+/// attempt are replaced with a message saying that no attempts are left.
+fn finish_verification(
+    user: Principal,
+    result: Result<Match, Error>,
+    attempts: u32,
+    config: &config::Config,
+) -> Verification {
+    match result {
+        Ok(result) => {
+            if result.matched {
+                RECOGNITION_RESULTS.with(|results| {
+                    results.borrow_mut().insert(
+                        user,
+                        RecognitionResult {
+                            label: result.label.clone(),
+                            score: result.distance,
+                            person: result.id,
+                        },
+                    );
+                });
+            }
+            Verification::Ok(result)
         }
         Err(e) => {
-            if attempts == MAX_ATTEMPTS {
-                Recognition::Err(Error {
-                    message: format!("Recognition failed after {} attempts", MAX_ATTEMPTS),
-                })
+            if attempts == config.max_attempts {
+                Verification::Err(Error::new(format!(
+                    "Recognition failed after {} attempts",
+                    config.max_attempts
+                )))
             } else {
-                Recognition::Err(Error {
-                    message: e.to_string(),
-                })
+                Verification::Err(e)
             }
         }
     }
 }
 
-/// Adds a person with the given name (label) and face (image) for future
-/// face recognition requests.
-// #[ic_cdk::update]
-// fn add(label: String, image: Vec<u8>, code: String) -> Addition {
-//     let caller = caller();
-
-//     // Check if the caller is anonymous
-//     if caller == Principal::anonymous() {
-//         return Addition::Err(Error {
-//             message: "Anonymous callers are not allowed".to_string(),
-//         });
-//     }
-
-//     if code != "qMu11Dfmw" {
-//         return Addition::Err(Error {
-//             message: "Unauthorized frontend access".to_string(),
-//         });
-//     }
-
-//     // Check if the function is enabled
-//     if !IS_ENABLED.with(|enabled| *enabled.borrow()) {
-//         return Addition::Err(Error {
-//             message: "This function is currently disabled".to_string(),
-//         });
-//     }
-
-//     // Check if the caller has already added a face
-//     if ADD_CALLERS.with(|callers| callers.borrow().contains(&caller)) {
-//         return Addition::Err(Error {
-//             message: "You have already added a face".to_string(),
-//         });
-//     }
-
-//     // Check if the maximum number of calls has been reached
-//     if ADD_COUNT.with(|count| *count.borrow() >= MAX_ADD_CALLS) {
-//         return Addition::Err(Error {
-//             message: "Maximum number of add calls reached".to_string(),
-//         });
-//     }
-
-//     // Perform the add operation
-//     let result = match onnx::add(label, image) {
-//         Ok(result) => {
-//             // Update the state
-//             ADD_CALLERS.with(|callers| callers.borrow_mut().insert(caller));
-//             ADD_COUNT.with(|count| *count.borrow_mut() += 1);
-//             Addition::Ok(result)
-//         },
-//         Err(err) => Addition::Err(Error {
-//             message: err.to_string(),
-//         }),
-//     };
-
-//     result
-// }
+/// Compares the given image against the face enrolled by the caller (1:1
+/// verification) and records the result on a match. Not available if the
+/// config requires a liveness session.
+#[ic_cdk::update]
+fn verify(image: Vec<u8>) -> Verification {
+    let caller = ic_cdk::caller();
+    if let Err(err) = check_can_verify(&caller) {
+        return Verification::Err(err);
+    }
+
+    let config = config::get();
+    if config.require_liveness_session() {
+        return Verification::Err(Error::new(
+            "Verification requires a liveness session, see start_liveness_session",
+        ));
+    }
+    let face = match prepare(image, &config, onnx::Purpose::Recognition) {
+        Ok(face) => face,
+        Err(err) => return Verification::Err(err),
+    };
+
+    let attempts = match consume_attempt(caller, &config) {
+        Ok(attempts) => attempts,
+        Err(err) => return Verification::Err(err),
+    };
+
+    let result = gallery::verify(caller, face, &config).map_err(Error::from);
+    finish_verification(caller, result, attempts, &config)
+}
+
+/// Starts a challenge-response liveness session for the caller. The caller
+/// must then submit one frame per challenge to `complete_liveness_session`.
+#[ic_cdk::update]
+async fn start_liveness_session() -> LivenessSession {
+    let caller = ic_cdk::caller();
+    if let Err(err) = check_can_verify(&caller) {
+        return LivenessSession::Err(err);
+    }
+    if !onnx::landmarks_loaded() {
+        return LivenessSession::Err(Error::new(
+            "Liveness sessions require the face landmarks model",
+        ));
+    }
+    let random = match raw_rand().await {
+        Ok((random,)) => random,
+        Err((_, message)) => {
+            return LivenessSession::Err(Error::new(format!(
+                "Failed to get randomness: {}",
+                message
+            )))
+        }
+    };
+    match liveness::start(caller, &random, api::time()) {
+        Ok(session) => LivenessSession::Ok(session),
+        Err(e) => LivenessSession::Err(Error::new(e)),
+    }
+}
 
+/// Checks that the given frames show the poses of the session in order and
+/// the same person throughout, then verifies the first frame against the
+/// face enrolled by the caller and records the result on a match.
+#[ic_cdk::update]
+fn complete_liveness_session(session_id: u64, frames: Vec<Vec<u8>>) -> Verification {
+    let caller = ic_cdk::caller();
+    if let Err(err) = check_can_verify(&caller) {
+        return Verification::Err(err);
+    }
+
+    let session = match liveness::take(session_id, &caller, api::time()) {
+        Ok(session) => session,
+        Err(e) => return Verification::Err(Error::new(e)),
+    };
+    if frames.len() != session.challenges.len() {
+        return Verification::Err(Error::new(format!(
+            "Expected {} frames, got {}",
+            session.challenges.len(),
+            frames.len()
+        )));
+    }
+
+    let config = config::get();
+    let mut faces = vec![];
+    for frame in frames {
+        match prepare(frame, &config, onnx::Purpose::Recognition) {
+            Ok(face) => faces.push(face),
+            Err(err) => return Verification::Err(err),
+        }
+    }
+
+    let attempts = match consume_attempt(caller, &config) {
+        Ok(attempts) => attempts,
+        Err(err) => return Verification::Err(err),
+    };
+
+    let result = check_liveness_frames(caller, &session, faces, &config);
+    finish_verification(caller, result, attempts, &config)
+}
+
+fn check_liveness_frames(
+    caller: Principal,
+    session: &liveness::Session,
+    faces: Vec<onnx::PreparedFace>,
+    config: &config::Config,
+) -> Result<Match, Error> {
+    let mut analyzed = vec![];
+    for (face, challenge) in faces.into_iter().zip(session.challenges.iter()) {
+        let (emb, face) = onnx::analyze(face, config)?;
+        let landmarks = face
+            .landmarks
+            .as_ref()
+            .ok_or_else(|| Error::new("Liveness sessions require the face landmarks model"))?;
+        if !liveness::satisfies(*challenge, landmarks) {
+            return Err(Error::new(format!(
+                "Frame {} does not show the {:?} challenge",
+                analyzed.len() + 1,
+                challenge
+            )));
+        }
+        analyzed.push((emb, face));
+    }
+
+    let (first, face) = analyzed.remove(0);
+    if analyzed
+        .iter()
+        .any(|(emb, _)| first.distance(emb, config.metric) > config.max_distance())
+    {
+        return Err(Error::new("The frames do not show the same person"));
+    }
+    Ok(gallery::verify_embedding(caller, &first, face, config)?)
+}
+
+/// Returns the closest person in the whole gallery (1:N identification).
+#[ic_cdk::update]
+fn recognize(image: Vec<u8>) -> Recognition {
+    if let Err(e) = require_admin() {
+        return Recognition::Err(Error::new(e));
+    }
+
+    let config = config::get();
+    let face = match prepare(image, &config, onnx::Purpose::Recognition) {
+        Ok(face) => face,
+        Err(err) => return Recognition::Err(err),
+    };
+
+    match gallery::recognize(face, &config) {
+        Ok(person) => Recognition::Ok(person),
+        Err(e) => Recognition::Err(Error::from(e)),
+    }
+}
+
+// The maximum number of people `recognize_top_k` returns.
+const MAX_TOP_K: u32 = 100;
+
+/// Returns the `k` closest people in the whole gallery with their distances,
+/// whether or not they are within the threshold.
+#[ic_cdk::update]
+fn recognize_top_k(image: Vec<u8>, k: u32) -> RankedRecognition {
+    if let Err(e) = require_admin() {
+        return RankedRecognition::Err(Error::new(e));
+    }
+    if k == 0 || k > MAX_TOP_K {
+        return RankedRecognition::Err(Error::new(format!(
+            "k must be between 1 and {}",
+            MAX_TOP_K
+        )));
+    }
+
+    let config = config::get();
+    let face = match prepare(image, &config, onnx::Purpose::Recognition) {
+        Ok(face) => face,
+        Err(err) => return RankedRecognition::Err(err),
+    };
+
+    match gallery::recognize_top_k(face, k as usize, &config) {
+        Ok(ranking) => RankedRecognition::Ok(ranking),
+        Err(e) => RankedRecognition::Err(Error::from(e)),
+    }
+}
+
+/// Adds a person with the given name (label) and face (image) for future
+/// face recognition requests. Users whose face was enrolled with different
+/// image processing may add their face again.
 #[ic_cdk::update]
 fn add(label: String, image: Vec<u8>, code: String) -> Addition {
     let caller = caller();
 
     if caller == Principal::anonymous() {
-        return Addition::Err(Error {
-            message: "Anonymous callers are not allowed".to_string(),
-        });
+        return Addition::Err(Error::new("Anonymous callers are not allowed"));
     }
 
-    if code != "qMu11Dfmw" {
-        return Addition::Err(Error {
-            message: "Unauthorized frontend access".to_string(),
-        });
+    // Users whose templates are outdated enroll again, see
+    // `gallery::OutdatedTemplates`. They were admitted before, so they need
+    // neither an invite nor a running campaign.
+    let enrolled = ADD_CALLERS.with(|callers| callers.borrow().contains_key(&caller));
+    if enrolled && gallery::check_up_to_date(caller, &config::get()).is_ok() {
+        return Addition::Err(Error::new("You have already added a face"));
     }
 
-    // Check if the function is enabled
-    if !IS_ENABLED.with(|enabled| *enabled.borrow()) {
-        return Addition::Err(Error {
-            message: "This function is currently disabled".to_string(),
-        });
-    }
+    let admission = if enrolled {
+        None
+    } else {
+        let invite = match invites::check(&code, &caller, api::time()) {
+            Ok(invite) => invite,
+            Err(e) => return Addition::Err(Error::new(e)),
+        };
 
-    if ADD_CALLERS.with(|callers| callers.borrow().contains(&caller)) {
-        return Addition::Err(Error {
-            message: "You have already added a face".to_string(),
-        });
+        let campaign = match campaigns::active(api::time()) {
+            Some(campaign) => campaign,
+            None => {
+                return Addition::Err(Error::new("No enrollment campaign is currently running"))
+            }
+        };
+
+        if campaign.enrolled >= campaign.cap {
+            return Addition::Err(Error::new(
+                "The enrollment campaign has reached its maximum number of enrollments",
+            ));
+        }
+        Some((invite, campaign))
+    };
+
+    // People are told apart by id, so labels only need to be valid, not
+    // unique.
+    if let Err(e) = people::normalize_label(&label) {
+        return Addition::Err(Error::new(e));
     }
 
-    if ADD_COUNT.with(|count| *count.borrow() >= MAX_ADD_CALLS) {
-        return Addition::Err(Error {
-            message: "Maximum number of add calls reached".to_string(),
-        });
+    if !onnx::models_loaded() {
+        return Addition::Err(Error::models_not_loaded());
     }
 
-    if RECOGNITION_RESULTS.with(|results| {
-        results
-            .borrow()
-            .values()
-            .any(|(rec_label, _)| rec_label == &label)
-    }) {
-        return Addition::Err(Error {
-            message: "This face has already been recognized and cannot be added again".to_string(),
-        });
+    let config = config::get();
+    if !enrolled
+        && config
+            .max_enrollments
+            .is_some_and(|max| gallery::gallery_size() >= max)
+    {
+        return Addition::Err(Error::new("Maximum number of enrollments reached"));
     }
+    let face = match prepare(image, &config, onnx::Purpose::Enrollment) {
+        Ok(face) => face,
+        Err(err) => return Addition::Err(err),
+    };
 
-    let result = match onnx::add(label, image) {
+    let result = match gallery::add(&label, caller, face, &config, api::time()) {
         Ok(result) => {
-            ADD_CALLERS.with(|callers| callers.borrow_mut().insert(caller));
-            ADD_COUNT.with(|count| *count.borrow_mut() += 1);
+            if let Some((invite, campaign)) = admission {
+                invites::consume(invite);
+                campaigns::record_enrollment(campaign.id, caller);
+                ADD_CALLERS.with(|callers| callers.borrow_mut().insert(caller, ()));
+            }
             Addition::Ok(result)
         }
-        Err(err) => Addition::Err(Error {
-            message: err.to_string(),
-        }),
+        Err(err) => Addition::Err(Error::from(err)),
     };
 
     result
 }
 
+/// Adds another image of the caller's face to their enrollment. The image
+/// must match all images enrolled so far.
 #[ic_cdk::update]
-fn toggle_add_function(enable: bool) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let admin =
-        Principal::from_text(ADMIN_PRINCIPAL).map_err(|_| "Invalid admin principal".to_string())?;
+fn add_template(image: Vec<u8>) -> TemplateAddition {
+    let caller = caller();
+    if !ADD_CALLERS.with(|callers| callers.borrow().contains_key(&caller)) {
+        return TemplateAddition::Err(Error::new("You have not added a face yet"));
+    }
+    if !onnx::models_loaded() {
+        return TemplateAddition::Err(Error::models_not_loaded());
+    }
+    let config = config::get();
+    match prepare(image, &config, onnx::Purpose::Enrollment)
+        .and_then(|face| Ok(gallery::add_template(caller, face, &config, api::time())?))
+    {
+        Ok(template) => TemplateAddition::Ok(template),
+        Err(err) => TemplateAddition::Err(err),
+    }
+}
 
-    if caller != admin {
-        return Err("Only the admin can toggle this function".to_string());
+/// Removes one of the caller's templates. The last one cannot be removed.
+#[ic_cdk::update]
+fn remove_template(id: u64) -> CanisterResponse<()> {
+    match gallery::remove_template(caller(), id, api::time()) {
+        Ok(()) => CanisterResponse::Ok(()),
+        Err(err) => CanisterResponse::Err(err.to_string()),
     }
+}
 
-    IS_ENABLED.with(|enabled| *enabled.borrow_mut() = enable);
-    Ok(())
+/// Returns the ids of the caller's templates.
+#[ic_cdk::query]
+fn my_templates() -> Vec<u64> {
+    gallery::template_ids(caller())
+}
+
+// The maximum number of people `list_people` returns.
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// Returns one page of the people in the gallery.
+#[ic_cdk::query]
+fn list_people(offset: u64, limit: u32) -> CanisterResponse<gallery::PeoplePage> {
+    if let Err(e) = auth::require_role(Role::Auditor) {
+        return CanisterResponse::Err(e);
+    }
+    CanisterResponse::Ok(gallery::list_people(
+        offset as usize,
+        limit.min(MAX_PAGE_SIZE) as usize,
+    ))
+}
+
+#[ic_cdk::query]
+fn get_person(id: people::PersonId) -> CanisterResponse<gallery::PersonInfo> {
+    match auth::require_role(Role::Auditor).and_then(|_| {
+        gallery::get_person(id).ok_or_else(|| format!("Person {} does not exist", id))
+    }) {
+        Ok(person) => CanisterResponse::Ok(person),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Renames the given person. The recognition result of the person, if any,
+/// is renamed as well.
+#[ic_cdk::update]
+fn relabel_person(id: people::PersonId, label: String) -> CanisterResponse<gallery::PersonInfo> {
+    if let Err(e) = auth::require_role(Role::EnrollmentManager) {
+        return CanisterResponse::Err(e);
+    }
+    match gallery::relabel(id, &label, api::time()) {
+        Ok(person) => {
+            RECOGNITION_RESULTS.with_borrow_mut(|results| {
+                let renamed: Vec<(Principal, RecognitionResult)> = results
+                    .iter()
+                    .filter(|(_, result)| result.person == person.record.id)
+                    .collect();
+                for (principal, mut result) in renamed {
+                    result.label = person.record.label.clone();
+                    results.insert(principal, result);
+                }
+            });
+            CanisterResponse::Ok(person)
+        }
+        Err(e) => CanisterResponse::Err(e.to_string()),
+    }
+}
+
+/// Replaces the free-form attributes of the given person.
+#[ic_cdk::update]
+fn set_person_attributes(
+    id: people::PersonId,
+    attributes: Vec<(String, String)>,
+) -> CanisterResponse<gallery::PersonInfo> {
+    match auth::require_role(Role::EnrollmentManager) {
+        Ok(_) => match gallery::set_attributes(id, attributes, api::time()) {
+            Ok(person) => CanisterResponse::Ok(person),
+            Err(e) => CanisterResponse::Err(e.to_string()),
+        },
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Removes the given person with all of their templates and everything
+/// recorded about their enrollment and recognition.
+#[ic_cdk::update]
+fn delete_person(id: people::PersonId) -> CanisterResponse<()> {
+    if let Err(e) = auth::require_role(Role::EnrollmentManager) {
+        return CanisterResponse::Err(e);
+    }
+    match gallery::delete_person(id) {
+        Ok(person) => {
+            forget_person(&person.record);
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e.to_string()),
+    }
+}
+
+/// Removes a single template of any person. Removing the last template of a
+/// person removes the person as `delete_person` does.
+#[ic_cdk::update]
+fn delete_template(id: u64) -> CanisterResponse<gallery::PersonInfo> {
+    if let Err(e) = auth::require_role(Role::EnrollmentManager) {
+        return CanisterResponse::Err(e);
+    }
+    match gallery::delete_template(id, api::time()) {
+        Ok(person) => {
+            if person.templates.is_empty() {
+                forget_person(&person.record);
+            }
+            CanisterResponse::Ok(person)
+        }
+        Err(e) => CanisterResponse::Err(e.to_string()),
+    }
+}
+
+/// Removes the enrollment, the attempts and the recognition results of a
+/// person that was removed from the gallery, so that the principal may enroll
+/// again.
+fn forget_person(person: &people::PersonRecord) {
+    forget_principal(&person.principal);
+    RECOGNITION_RESULTS.with_borrow_mut(|results| {
+        let stale: Vec<Principal> = results
+            .iter()
+            .filter(|(_, result)| result.person == person.id)
+            .map(|(principal, _)| principal)
+            .collect();
+        for principal in stale {
+            results.remove(&principal);
+        }
+    });
+}
+
+/// Removes the enrollment, the campaign enrollment, the recognition attempts
+/// and the recognition result of the given principal.
+fn forget_principal(principal: &Principal) {
+    ADD_CALLERS.with_borrow_mut(|callers| callers.remove(principal));
+    campaigns::forget(principal);
+    RECOGNITION_ATTEMPTS.with_borrow_mut(|attempts| attempts.remove(principal));
+    RECOGNITION_RESULTS.with_borrow_mut(|results| results.remove(principal));
+}
+
+/// Everything the canister stores about a user.
+#[derive(CandidType, Deserialize)]
+struct MyData {
+    principal: Principal,
+    people: Vec<gallery::PersonInfo>,
+    // Only set if requested.
+    embeddings: Option<Vec<(u64, onnx::Embedding)>>,
+    enrolled: bool,
+    campaign: Option<u64>,
+    invites: Vec<invites::Invite>,
+    recognition_attempts: u32,
+    recognition_result: Option<RecognitionResult>,
+    roles: Vec<Role>,
+    audit_events: Vec<audit::AuditEvent>,
+}
+
+/// Returns everything the canister stores about the caller. This is an update
+/// rather than a query so that the export is recorded in the audit trail. Only
+/// the people the caller enrolled are included.
+#[ic_cdk::update]
+fn export_my_data(include_embeddings: bool) -> CanisterResponse<MyData> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return CanisterResponse::Err("Anonymous callers are not allowed".to_string());
+    }
+    audit::record(caller, audit::AuditAction::DataExported, api::time());
+    let people = gallery::people_of(caller);
+    let templates: Vec<u64> = people
+        .iter()
+        .flat_map(|person| person.templates.iter().copied())
+        .collect();
+    CanisterResponse::Ok(MyData {
+        principal: caller,
+        people,
+        embeddings: include_embeddings.then(|| gallery::embeddings_of(&templates)),
+        enrolled: ADD_CALLERS.with_borrow(|callers| callers.contains_key(&caller)),
+        campaign: campaigns::enrollment_of(&caller),
+        invites: invites::list()
+            .into_iter()
+            .filter(|invite| invite.principal == Some(caller))
+            .collect(),
+        recognition_attempts: RECOGNITION_ATTEMPTS
+            .with_borrow(|attempts| attempts.get(&caller).unwrap_or(0)),
+        recognition_result: RECOGNITION_RESULTS.with_borrow(|results| results.get(&caller)),
+        roles: auth::roles(&caller),
+        audit_events: audit::events_of(&caller),
+    })
+}
+
+/// Removes the people the caller enrolled with their templates, and the
+/// caller's enrollment, recognition attempts and recognition result. People
+/// the caller did not enroll are not the caller's to erase, even if the caller
+/// was recognized as them: an enrollment manager removes them with
+/// `delete_person`. Enrolling again requires a new invite.
+#[ic_cdk::update]
+fn delete_my_data() -> CanisterResponse<()> {
+    let caller = caller();
+    if caller == Principal::anonymous() {
+        return CanisterResponse::Err("Anonymous callers are not allowed".to_string());
+    }
+    let mut templates = 0;
+    for person in gallery::people_of(caller) {
+        match gallery::delete_person(person.record.id) {
+            Ok(person) => {
+                templates += person.templates.len() as u64;
+                forget_person(&person.record);
+            }
+            Err(e) => return CanisterResponse::Err(e.to_string()),
+        }
+    }
+    forget_principal(&caller);
+    audit::record(
+        caller,
+        audit::AuditAction::DataDeleted { templates },
+        api::time(),
+    );
+    CanisterResponse::Ok(())
+}
+
+/// Returns the record of users exporting and deleting their data.
+#[ic_cdk::query]
+fn get_audit_trail() -> CanisterResponse<Vec<audit::AuditEvent>> {
+    match auth::require_role(Role::Auditor) {
+        Ok(_) => CanisterResponse::Ok(audit::events()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Creates an invite that allows enrolling through `add`. The returned code
+/// is not stored and cannot be retrieved again.
+#[ic_cdk::update]
+async fn create_invite(args: invites::InviteArgs) -> CanisterResponse<invites::CreatedInvite> {
+    if let Err(e) = auth::require_role(Role::EnrollmentManager) {
+        return CanisterResponse::Err(e);
+    }
+    let random = match raw_rand().await {
+        Ok((random,)) => random,
+        Err((_, message)) => {
+            return CanisterResponse::Err(format!("Failed to get randomness: {}", message))
+        }
+    };
+    match invites::create(args, &random, ic_cdk::caller(), api::time()) {
+        Ok(invite) => CanisterResponse::Ok(invite),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::query]
+fn list_invites() -> CanisterResponse<Vec<invites::Invite>> {
+    match auth::require_role(Role::EnrollmentManager) {
+        Ok(_) => CanisterResponse::Ok(invites::list()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn revoke_invite(id: u64) -> CanisterResponse<()> {
+    match auth::require_role(Role::EnrollmentManager).and_then(|_| invites::revoke(id)) {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn create_campaign(args: campaigns::CampaignArgs) -> CanisterResponse<campaigns::Campaign> {
+    match auth::require_role(Role::EnrollmentManager)
+        .and_then(|_| campaigns::create(args, api::time()))
+    {
+        Ok(campaign) => CanisterResponse::Ok(campaign),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn delete_campaign(id: u64) -> CanisterResponse<()> {
+    match auth::require_role(Role::EnrollmentManager).and_then(|_| campaigns::delete(id)) {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Returns the enrollment campaigns that are running or have not started yet.
+#[ic_cdk::query]
+fn list_campaigns() -> Vec<campaigns::Campaign> {
+    campaigns::current_and_upcoming(api::time())
+}
+
+#[ic_cdk::query]
+fn get_campaign_enrollments(id: u64) -> CanisterResponse<Vec<Principal>> {
+    match auth::require_role(Role::Auditor) {
+        Ok(_) => CanisterResponse::Ok(campaigns::enrollments(id)),
+        Err(e) => CanisterResponse::Err(e),
+    }
 }
 
 #[ic_cdk::update]
 fn clear_face_detection_model_bytes() -> CanisterResponse<()> {
-    match require_admin() {
+    match auth::require_role(Role::ModelManager) {
         Ok(_) => {
             storage::clear_bytes(FACE_DETECTION_FILE);
             CanisterResponse::Ok(())
@@ -271,7 +980,7 @@ fn clear_face_detection_model_bytes() -> CanisterResponse<()> {
 
 #[ic_cdk::update]
 fn clear_face_recognition_model_bytes() -> CanisterResponse<()> {
-    match require_admin() {
+    match auth::require_role(Role::ModelManager) {
         Ok(_) => {
             storage::clear_bytes(FACE_RECOGNITION_FILE);
             CanisterResponse::Ok(())
@@ -282,7 +991,7 @@ fn clear_face_recognition_model_bytes() -> CanisterResponse<()> {
 
 #[ic_cdk::update]
 fn append_face_detection_model_bytes(bytes: Vec<u8>) -> CanisterResponse<()> {
-    match require_admin() {
+    match auth::require_role(Role::ModelManager) {
         Ok(_) => {
             storage::append_bytes(FACE_DETECTION_FILE, bytes);
             CanisterResponse::Ok(())
@@ -293,7 +1002,7 @@ fn append_face_detection_model_bytes(bytes: Vec<u8>) -> CanisterResponse<()> {
 
 #[ic_cdk::update]
 fn append_face_recognition_model_bytes(bytes: Vec<u8>) -> CanisterResponse<()> {
-    match require_admin() {
+    match auth::require_role(Role::ModelManager) {
         Ok(_) => {
             storage::append_bytes(FACE_RECOGNITION_FILE, bytes);
             CanisterResponse::Ok(())
@@ -302,32 +1011,218 @@ fn append_face_recognition_model_bytes(bytes: Vec<u8>) -> CanisterResponse<()> {
     }
 }
 
+#[ic_cdk::update]
+fn clear_face_landmarks_model_bytes() -> CanisterResponse<()> {
+    match auth::require_role(Role::ModelManager) {
+        Ok(_) => {
+            storage::clear_bytes(FACE_LANDMARKS_FILE);
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn append_face_landmarks_model_bytes(bytes: Vec<u8>) -> CanisterResponse<()> {
+    match auth::require_role(Role::ModelManager) {
+        Ok(_) => {
+            storage::append_bytes(FACE_LANDMARKS_FILE, bytes);
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn clear_face_antispoof_model_bytes() -> CanisterResponse<()> {
+    match auth::require_role(Role::ModelManager) {
+        Ok(_) => {
+            storage::clear_bytes(FACE_ANTISPOOF_FILE);
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn append_face_antispoof_model_bytes(bytes: Vec<u8>) -> CanisterResponse<()> {
+    match auth::require_role(Role::ModelManager) {
+        Ok(_) => {
+            storage::append_bytes(FACE_ANTISPOOF_FILE, bytes);
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Loads the uploaded models. The landmark and anti-spoofing models are
+/// optional and are only loaded if their bytes have been uploaded.
 #[ic_cdk::update]
 fn setup_models() -> CanisterResponse<()> {
-    match require_admin() {
+    match auth::require_role(Role::ModelManager) {
         Ok(_) => {
             match setup(
                 storage::bytes(FACE_DETECTION_FILE),
                 storage::bytes(FACE_RECOGNITION_FILE),
+                storage::exists(FACE_LANDMARKS_FILE).then(|| storage::bytes(FACE_LANDMARKS_FILE)),
+                storage::exists(FACE_ANTISPOOF_FILE).then(|| storage::bytes(FACE_ANTISPOOF_FILE)),
             ) {
-                Ok(_) => CanisterResponse::Ok(()),
-                Err(err) => CanisterResponse::Err(format!("Failed to setup model: {}", err)),
+                Ok(_) => {
+                    set_model_status(ModelStatus::Ready);
+                    CanisterResponse::Ok(())
+                }
+                Err(err) => {
+                    let message = format!("Failed to setup model: {}", err);
+                    set_model_status(ModelStatus::Failed(message.clone()));
+                    CanisterResponse::Err(message)
+                }
             }
         }
         Err(e) => CanisterResponse::Err(e),
     }
 }
 
+fn set_model_status(status: ModelStatus) {
+    MODEL_STATUS.with(|s| *s.borrow_mut() = status);
+}
+
+type ModelLoader = fn(bytes::Bytes) -> anyhow::Result<()>;
+
+/// Loads the given models, one per timer message, and marks the models as
+/// ready once all of them are loaded.
+fn load_models_in_timers(mut pending: Vec<(&'static str, &'static str, ModelLoader)>) {
+    if pending.is_empty() {
+        set_model_status(ModelStatus::Ready);
+        return;
+    }
+    let (name, file, loader) = pending.remove(0);
+    ic_cdk_timers::set_timer(Duration::ZERO, move || match loader(storage::bytes(file)) {
+        Ok(_) => load_models_in_timers(pending),
+        Err(err) => set_model_status(ModelStatus::Failed(format!(
+            "Failed to load the {} model: {}",
+            name, err
+        ))),
+    });
+}
+
+/// Reloads the models from the files stored in the WASI filesystem, if the
+/// required ones exist. Each model is loaded in its own timer message because
+/// loading all of them in a single message may exceed the instruction limit.
+fn schedule_model_reload() {
+    if !storage::exists(FACE_DETECTION_FILE) || !storage::exists(FACE_RECOGNITION_FILE) {
+        return;
+    }
+    set_model_status(ModelStatus::Loading);
+    let mut pending: Vec<(&str, &str, ModelLoader)> = vec![
+        (
+            "face detection",
+            FACE_DETECTION_FILE,
+            onnx::setup_facedetect,
+        ),
+        (
+            "face recognition",
+            FACE_RECOGNITION_FILE,
+            onnx::setup_facerec,
+        ),
+    ];
+    if storage::exists(FACE_LANDMARKS_FILE) {
+        pending.push(("face landmarks", FACE_LANDMARKS_FILE, onnx::setup_landmarks));
+    }
+    if storage::exists(FACE_ANTISPOOF_FILE) {
+        pending.push(("anti-spoofing", FACE_ANTISPOOF_FILE, onnx::setup_antispoof));
+    }
+    load_models_in_timers(pending);
+}
+
+// The number of templates linked into the index per timer message while the
+// index is being rebuilt.
+const INDEX_REBUILD_BATCH: usize = 50;
+
+/// Continues rebuilding the index, one batch of templates per timer message,
+/// because rebuilding a large gallery in a single message exceeds the
+/// instruction limit. Does nothing if the index is complete or the next batch
+/// is already scheduled.
+fn schedule_index_rebuild() {
+    if !gallery::index_rebuilding() || INDEX_REBUILD_SCHEDULED.replace(true) {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        INDEX_REBUILD_SCHEDULED.set(false);
+        gallery::continue_index_rebuild(config::get().metric, INDEX_REBUILD_BATCH);
+        schedule_index_rebuild();
+    });
+}
+
+/// Brings the stable memory layout up to `STATE_VERSION`.
+fn migrate_state() {
+    STATE_VERSION_CELL.with(|version| {
+        let mut version = version.borrow_mut();
+        let stored = *version.get();
+        if stored > STATE_VERSION {
+            ic_cdk::trap(&format!(
+                "Cannot downgrade the state from version {} to {}",
+                stored, STATE_VERSION
+            ));
+        }
+        if stored == 0 {
+            // Version 0 kept its state on the heap, so nothing survives the
+            // upgrade but the admin it had hard-coded.
+            auth::migrate_legacy_admin();
+        }
+        version
+            .set(STATE_VERSION)
+            .expect("failed to update the state version");
+    });
+}
+
+/// The argument of `init` and `post_upgrade`.
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    // Replaces the stored admins if set. Controllers are always admins.
+    admins: Option<Vec<Principal>>,
+    // Replaces the stored config if set.
+    config: Option<config::Config>,
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        if let Some(admins) = args.admins {
+            if let Err(err) = auth::set_admins(admins) {
+                ic_cdk::trap(&err);
+            }
+        }
+        if let Some(config) = args.config {
+            if let Err(err) = check_gallery_metric(&config)
+                .and_then(|_| config::set(config, ic_cdk::caller(), api::time()))
+            {
+                ic_cdk::trap(&err);
+            }
+        }
+    }
+}
+
 #[ic_cdk::init]
-fn init() {
-    let wasi_memory = MEMORY_MANAGER.with(|m| m.borrow().get(WASI_MEMORY_ID));
+fn init(args: Option<InitArgs>) {
+    let wasi_memory = memory(WASI_MEMORY_ID);
     ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
+    // A new canister starts out with the current layout.
+    STATE_VERSION_CELL.with_borrow_mut(|version| {
+        version
+            .set(STATE_VERSION)
+            .expect("failed to update the state version")
+    });
+    apply_init_args(args);
+    schedule_model_reload();
 }
 
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    let wasi_memory = MEMORY_MANAGER.with(|m| m.borrow().get(WASI_MEMORY_ID));
+fn post_upgrade(args: Option<InitArgs>) {
+    let wasi_memory = memory(WASI_MEMORY_ID);
     ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
+    migrate_state();
+    apply_init_args(args);
+    schedule_model_reload();
+    schedule_index_rebuild();
 }
 
 #[derive(CandidType, Deserialize)]
@@ -338,64 +1233,201 @@ pub enum CanisterResponse<T> {
 
 #[ic_cdk::query]
 fn is_authorized() -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let admin =
-        Principal::from_text(ADMIN_PRINCIPAL).map_err(|_| "Invalid admin principal".to_string())?;
+    require_admin()
+}
 
-    if caller != admin {
-        Err("Unauthorized: only the admin can perform this action".to_string())
-    } else {
-        Ok(())
-    }
+/// Returns whether the models are loaded and the canister is ready to serve
+/// detection and recognition requests.
+#[ic_cdk::query]
+fn model_status() -> ModelStatus {
+    MODEL_STATUS.with(|s| s.borrow().clone())
 }
 
+/// Returns the recognition result of the given user. Users may read their
+/// own result, anyone else needs the auditor role.
 #[ic_cdk::query]
-fn get_recognition_result(user: Principal) -> Option<RecognitionResult> {
-    RECOGNITION_RESULTS.with(|results| {
-        results
-            .borrow()
-            .get(&user)
-            .map(|(label, score)| RecognitionResult {
-                label: label.clone(),
-                score: *score,
-            })
-    })
+fn get_recognition_result(user: Principal) -> CanisterResponse<Option<RecognitionResult>> {
+    if ic_cdk::caller() != user {
+        if let Err(e) = auth::require_role(Role::Auditor) {
+            return CanisterResponse::Err(e);
+        }
+    }
+    CanisterResponse::Ok(RECOGNITION_RESULTS.with(|results| results.borrow().get(&user)))
 }
 
 fn require_admin() -> Result<(), String> {
+    auth::require_admin()
+}
+
+#[ic_cdk::update]
+fn add_admin(admin: Principal) -> CanisterResponse<()> {
+    match require_admin().and_then(|_| auth::add_admin(admin)) {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn remove_admin(admin: Principal) -> CanisterResponse<()> {
+    match require_admin().and_then(|_| auth::remove_admin(&admin)) {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Makes `new_admin` an admin and revokes the admin rights of the caller.
+#[ic_cdk::update]
+fn transfer_admin(new_admin: Principal) -> CanisterResponse<()> {
     let caller = ic_cdk::caller();
-    let admin =
-        Principal::from_text(ADMIN_PRINCIPAL).map_err(|_| "Invalid admin principal".to_string())?;
+    match require_admin().and_then(|_| auth::add_admin(new_admin)) {
+        Ok(_) => {
+            if caller != new_admin {
+                // A controller that is not a stored admin has nothing to revoke.
+                let _ = auth::remove_admin(&caller);
+            }
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
 
-    if caller != admin {
-        Err("Unauthorized: only the admin can perform this action".to_string())
-    } else {
-        Ok(())
+#[ic_cdk::update]
+fn update_config(config: config::Config) -> CanisterResponse<()> {
+    match require_admin()
+        .and_then(|_| check_gallery_metric(&config))
+        .and_then(|_| config::set(config, ic_cdk::caller(), api::time()))
+    {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Rebuilds the approximate search index from the gallery, for example after
+/// changing the index parameters. The index is rebuilt in the background and
+/// identification uses exact search until it is complete.
+#[ic_cdk::update]
+fn rebuild_index() -> CanisterResponse<()> {
+    match require_admin() {
+        Ok(_) => {
+            gallery::start_index_rebuild();
+            schedule_index_rebuild();
+            CanisterResponse::Ok(())
+        }
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Converts up to `limit` gallery templates to the configured precision and
+/// returns the number of templates left to convert. Call repeatedly until it
+/// returns zero.
+#[ic_cdk::update]
+fn requantize_gallery(limit: u32) -> CanisterResponse<u64> {
+    match require_admin() {
+        Ok(_) => CanisterResponse::Ok(gallery::requantize(
+            config::get().embedding_precision(),
+            limit as usize,
+        )),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Rejects a config whose metric differs from the one the gallery was
+/// enrolled with, because the stored embeddings could not be compared.
+fn check_gallery_metric(config: &config::Config) -> Result<(), String> {
+    match gallery::gallery_metric() {
+        Some(metric) if metric != config.metric => Err(format!(
+            "The gallery was enrolled with the {:?} metric and cannot be compared with {:?}",
+            metric, config.metric
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[ic_cdk::query]
+fn get_config() -> config::Config {
+    config::get()
+}
+
+/// Returns every config change with who made it and when.
+#[ic_cdk::query]
+fn get_config_history() -> CanisterResponse<Vec<config::ConfigChange>> {
+    match auth::require_role(Role::Auditor) {
+        Ok(_) => CanisterResponse::Ok(config::history()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> CanisterResponse<()> {
+    match require_admin().and_then(|_| auth::grant_role(principal, role)) {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> CanisterResponse<()> {
+    match require_admin().and_then(|_| auth::revoke_role(&principal, role)) {
+        Ok(_) => CanisterResponse::Ok(()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+#[ic_cdk::query]
+fn list_roles() -> CanisterResponse<Vec<(Principal, Vec<Role>)>> {
+    match require_admin() {
+        Ok(_) => CanisterResponse::Ok(auth::all_roles()),
+        Err(e) => CanisterResponse::Err(e),
+    }
+}
+
+/// Returns the roles held by the caller.
+#[ic_cdk::query]
+fn my_roles() -> Vec<Role> {
+    auth::roles(&ic_cdk::caller())
+}
+
+#[ic_cdk::query]
+fn list_admins() -> CanisterResponse<Vec<Principal>> {
+    match require_admin() {
+        Ok(_) => CanisterResponse::Ok(auth::admins()),
+        Err(e) => CanisterResponse::Err(e),
     }
 }
 
 #[ic_cdk::query]
-fn get_add_callers() -> (u64, Vec<Principal>) {
-    let callers =
-        ADD_CALLERS.with(|callers| callers.borrow().iter().cloned().collect::<Vec<Principal>>());
+fn get_add_callers() -> CanisterResponse<(u64, Vec<Principal>)> {
+    if let Err(e) = auth::require_role(Role::Auditor) {
+        return CanisterResponse::Err(e);
+    }
+    let callers = ADD_CALLERS.with(|callers| {
+        callers
+            .borrow()
+            .iter()
+            .map(|(caller, _)| caller)
+            .collect::<Vec<Principal>>()
+    });
     let count = callers.len() as u64;
-    (count, callers)
+    CanisterResponse::Ok((count, callers))
 }
 
 #[ic_cdk::query]
-fn get_all_recognition_results() -> Vec<String> {
-    RECOGNITION_RESULTS.with(|results| {
+fn get_all_recognition_results() -> CanisterResponse<Vec<String>> {
+    if let Err(e) = auth::require_role(Role::Auditor) {
+        return CanisterResponse::Err(e);
+    }
+    CanisterResponse::Ok(RECOGNITION_RESULTS.with(|results| {
         results
             .borrow()
             .iter()
-            .map(|(principal, (label, score))| {
+            .map(|(principal, result)| {
                 format!(
                     "principal: {}, label: {}, score: {}",
-                    principal, label, score
+                    principal, result.label, result.score
                 )
             })
             .collect()
-    })
+    }))
 }
 
 #[ic_cdk::query]
@@ -409,3 +1441,43 @@ fn add_cycles() {
     let accepted_cycles = api::call::msg_cycles_accept(available_cycles);
     ic_cdk::println!("Accepted {} cycles", accepted_cycles);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_version(stored: u32) {
+        STATE_VERSION_CELL.with_borrow_mut(|version| version.set(stored).unwrap());
+    }
+
+    fn version() -> u32 {
+        STATE_VERSION_CELL.with_borrow(|version| *version.get())
+    }
+
+    fn legacy_admin() -> Principal {
+        Principal::from_text("4s4hz-og66m-hypzp-uxv6q-addgn-hshem-dnvln-uhy7t-h3hsc-pmajb-mqe")
+            .unwrap()
+    }
+
+    #[test]
+    fn keeps_the_hard_coded_admin_of_the_heap_layout() {
+        migrate_state();
+        assert_eq!(version(), STATE_VERSION);
+        assert_eq!(auth::admins(), vec![legacy_admin()]);
+    }
+
+    #[test]
+    fn leaves_the_current_layout_alone() {
+        set_version(STATE_VERSION);
+        migrate_state();
+        assert_eq!(version(), STATE_VERSION);
+        assert!(auth::admins().is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn refuses_to_downgrade() {
+        set_version(STATE_VERSION + 1);
+        migrate_state();
+    }
+}
@@ -0,0 +1,188 @@
+use crate::onnx::Landmarks;
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Sessions are short-lived, so they are kept on the heap and an upgrade simply
+// invalidates the running ones.
+thread_local! {
+    static SESSIONS: RefCell<BTreeMap<u64, Session>> = RefCell::new(BTreeMap::new());
+}
+
+// How long a client has to submit the frames of a session.
+const SESSION_TTL_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+// The head yaw estimated from the landmarks, as a fraction of the distance
+// between the eyes, beyond which the head counts as turned.
+const TURNED_YAW: f32 = 0.15;
+// The yaw below which the head counts as looking straight.
+const STRAIGHT_YAW: f32 = 0.08;
+
+/// A head pose the user must show in one frame. Left and right are as seen in
+/// the submitted image, so mirrored previews must be taken into account by the
+/// client.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Challenge {
+    LookStraight,
+    TurnLeft,
+    TurnRight,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Session {
+    pub id: u64,
+    pub principal: Principal,
+    // The poses to show, one frame per pose, in order.
+    pub challenges: Vec<Challenge>,
+    pub expires_at: u64,
+}
+
+/// Starts a session for the given principal with challenges derived from the
+/// given random bytes, replacing any session the principal already has.
+pub fn start(principal: Principal, random: &[u8], now: u64) -> Result<Session, String> {
+    if random.len() < 10 {
+        return Err("Not enough randomness to start a session".to_string());
+    }
+    let id = u64::from_le_bytes(random[..8].try_into().unwrap());
+
+    // Always start with a frontal frame, then turn the head left and right in
+    // a random order, two or three times.
+    let mut turn = if random[8] & 1 == 0 {
+        Challenge::TurnLeft
+    } else {
+        Challenge::TurnRight
+    };
+    let mut challenges = vec![Challenge::LookStraight];
+    for _ in 0..2 + (random[9] & 1) {
+        challenges.push(turn);
+        turn = match turn {
+            Challenge::TurnLeft => Challenge::TurnRight,
+            _ => Challenge::TurnLeft,
+        };
+    }
+
+    let session = Session {
+        id,
+        principal,
+        challenges,
+        expires_at: now + SESSION_TTL_NANOS,
+    };
+    SESSIONS.with_borrow_mut(|sessions| {
+        sessions.retain(|_, s| s.expires_at > now && s.principal != principal);
+        sessions.insert(id, session.clone());
+    });
+    Ok(session)
+}
+
+/// Removes and returns the given session if it belongs to the given principal
+/// and has not expired. A session can only be completed once.
+pub fn take(id: u64, principal: &Principal, now: u64) -> Result<Session, String> {
+    let session = SESSIONS
+        .with_borrow_mut(|sessions| match sessions.get(&id) {
+            Some(session) if session.principal == *principal => sessions.remove(&id),
+            _ => None,
+        })
+        .ok_or("Unknown liveness session")?;
+    if session.expires_at <= now {
+        return Err("The liveness session has expired".to_string());
+    }
+    Ok(session)
+}
+
+/// Estimates the head yaw from the position of the nose relative to the eyes.
+/// Negative values mean the head is turned towards the left of the image.
+fn yaw(landmarks: &Landmarks) -> f32 {
+    let eyes_x = (landmarks.left_eye.x + landmarks.right_eye.x) / 2.0;
+    let eye_distance = ((landmarks.right_eye.x - landmarks.left_eye.x).powi(2)
+        + (landmarks.right_eye.y - landmarks.left_eye.y).powi(2))
+    .sqrt();
+    if eye_distance == 0.0 {
+        return 0.0;
+    }
+    (landmarks.nose.x - eyes_x) / eye_distance
+}
+
+/// Returns true if the given landmarks show the pose the challenge asks for.
+pub fn satisfies(challenge: Challenge, landmarks: &Landmarks) -> bool {
+    let yaw = yaw(landmarks);
+    match challenge {
+        Challenge::LookStraight => yaw.abs() < STRAIGHT_YAW,
+        Challenge::TurnLeft => yaw < -TURNED_YAW,
+        Challenge::TurnRight => yaw > TURNED_YAW,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::onnx::Point;
+
+    // Eyes 40px apart with the nose shifted sideways by the given yaw.
+    fn landmarks(yaw: f32) -> Landmarks {
+        let point = |x, y| Point { x, y };
+        Landmarks {
+            left_eye: point(30.0, 40.0),
+            right_eye: point(70.0, 40.0),
+            nose: point(50.0 + 40.0 * yaw, 60.0),
+            left_mouth: point(35.0, 80.0),
+            right_mouth: point(65.0, 80.0),
+        }
+    }
+
+    #[test]
+    fn checks_the_head_pose() {
+        assert!(satisfies(Challenge::LookStraight, &landmarks(0.0)));
+        assert!(!satisfies(Challenge::LookStraight, &landmarks(0.1)));
+        assert!(satisfies(Challenge::TurnLeft, &landmarks(-0.2)));
+        assert!(!satisfies(Challenge::TurnLeft, &landmarks(0.2)));
+        assert!(satisfies(Challenge::TurnRight, &landmarks(0.2)));
+        // Between looking straight and turned counts as neither.
+        assert!(!satisfies(Challenge::TurnRight, &landmarks(0.1)));
+    }
+
+    #[test]
+    fn starts_straight_then_alternates_turns() {
+        let principal = Principal::from_slice(&[1]);
+        let session = start(principal, &[7, 0, 0, 0, 0, 0, 0, 0, 1, 1], 0).unwrap();
+        assert_eq!(session.id, 7);
+        assert_eq!(
+            session.challenges,
+            vec![
+                Challenge::LookStraight,
+                Challenge::TurnRight,
+                Challenge::TurnLeft,
+                Challenge::TurnRight
+            ]
+        );
+        let session = start(principal, &[8, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0).unwrap();
+        assert_eq!(
+            session.challenges,
+            vec![
+                Challenge::LookStraight,
+                Challenge::TurnLeft,
+                Challenge::TurnRight
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_enough_randomness() {
+        assert!(start(Principal::anonymous(), &[0; 9], 0).is_err());
+    }
+
+    #[test]
+    fn completes_a_session_once() {
+        let principal = Principal::from_slice(&[1]);
+        let session = start(principal, &[1; 10], 0).unwrap();
+        assert!(take(session.id, &Principal::anonymous(), 1).is_err());
+        assert!(take(session.id, &principal, 1).is_ok());
+        assert!(take(session.id, &principal, 1).is_err());
+    }
+
+    #[test]
+    fn expires_sessions() {
+        let principal = Principal::from_slice(&[1]);
+        let session = start(principal, &[2; 10], 0).unwrap();
+        assert!(take(session.id, &principal, session.expires_at).is_err());
+    }
+}
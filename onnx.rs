@@ -1,23 +1,34 @@
+use crate::config::{Config, DistanceMetric, EmbeddingPrecision};
+use crate::quality::{self, Quality};
+use crate::quantization::Quantized;
 use anyhow::anyhow;
 use bytes::Bytes;
-use candid::CandidType;
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{storable::Bound, Storable};
+use image::RgbImage;
 use prost::Message;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::cell::RefCell;
 use tract_ndarray::s;
 use tract_onnx::prelude::*;
 
-// The maximum distance between face embeddings of the same person.
-const THRESHOLD: f32 = 0.85;
-
 type Model = SimplePlan<TypedFact, Box<dyn TypedOp>, Graph<TypedFact, Box<dyn TypedOp>>>;
 
 thread_local! {
     static FACE_DETECTION: RefCell<Option<Model>> = RefCell::new(None);
     static FACE_RECOGNITION: RefCell<Option<Model>> = RefCell::new(None);
-    static DB: RefCell<Vec<(String, Embedding)>> = RefCell::new(vec![]);
+    // Identifies the loaded recognition model, see `model_version`.
+    static FACE_RECOGNITION_VERSION: RefCell<Option<String>> = RefCell::new(None);
+    // The landmark model is optional. Faces are not aligned without it, so
+    // loading or unloading it requires all faces to be enrolled again.
+    static FACE_LANDMARKS: RefCell<Option<Model>> = RefCell::new(None);
+    // The anti-spoofing model is optional. Liveness is not checked without it.
+    static FACE_ANTISPOOF: RefCell<Option<Model>> = RefCell::new(None);
 }
 
+/// A box around a face in the pixel coordinates of the original image.
 #[derive(CandidType, Deserialize, Clone)]
 pub struct BoundingBox {
     left: f32,
@@ -35,69 +46,495 @@ impl BoundingBox {
             bottom: raw[3],
         }
     }
+
+    /// Converts a box in coordinates relative to the image size into pixels.
+    fn scale(&self, width: f32, height: f32) -> Self {
+        Self {
+            left: self.left * width,
+            top: self.top * height,
+            right: self.right * width,
+            bottom: self.bottom * height,
+        }
+    }
+
+    fn area(&self) -> f32 {
+        (self.right - self.left).max(0.0) * (self.bottom - self.top).max(0.0)
+    }
+
+    /// Returns the intersection over union of the two boxes.
+    fn iou(&self, other: &Self) -> f32 {
+        let intersection = Self {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        }
+        .area();
+        let union = self.area() + other.area() - intersection;
+        if union <= 0.0 {
+            return 0.0;
+        }
+        intersection / union
+    }
+
+    /// Grows the box by `margin` times its size on every side, clipped to the
+    /// image.
+    fn expand(&self, margin: f32, width: f32, height: f32) -> Self {
+        let dx = (self.right - self.left) * margin;
+        let dy = (self.bottom - self.top) * margin;
+        Self {
+            left: (self.left - dx).max(0.0),
+            top: (self.top - dy).max(0.0),
+            right: (self.right + dx).min(width),
+            bottom: (self.bottom + dy).min(height),
+        }
+    }
+}
+
+/// A face found by the detection model.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FaceDetection {
+    pub bounding_box: BoundingBox,
+    pub confidence: f32,
+}
+
+/// A point in the pixel coordinates of the original image.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+/// The five facial landmarks used for alignment. Left and right are from the
+/// point of view of the image.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Landmarks {
+    pub left_eye: Point,
+    pub right_eye: Point,
+    pub nose: Point,
+    pub left_mouth: Point,
+    pub right_mouth: Point,
+}
+
+impl Landmarks {
+    fn points(&self) -> [Point; 5] {
+        [
+            self.left_eye,
+            self.right_eye,
+            self.nose,
+            self.left_mouth,
+            self.right_mouth,
+        ]
+    }
+}
+
+/// The face an embedding was computed from.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DetectedFace {
+    pub bounding_box: BoundingBox,
+    // Only set if the landmark model is loaded and the face was aligned.
+    pub landmarks: Option<Landmarks>,
+    // The probability that the face is live rather than a photo or a screen.
+    // Only set for recognition with the anti-spoofing model loaded.
+    pub liveness: Option<f32>,
+}
+
+/// A face cropped out of an uploaded image.
+struct FaceCrop {
+    image: RgbImage,
+    face: BoundingBox,
+}
+
+/// What an image is prepared for, which decides how its face is chosen.
+#[derive(Clone, Copy)]
+pub enum Purpose {
+    Recognition,
+    Enrollment,
+}
+
+/// A decoded image with its face located and assessed, ready to be embedded.
+/// Preparing is separate from embedding so that callers can reject an image
+/// on its quality before doing anything that has side effects.
+pub struct PreparedFace {
+    image: RgbImage,
+    crop: FaceCrop,
+    pub quality: Quality,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Embedding {
     v0: Vec<f32>,
+    // Set instead of `v0` for gallery templates stored with reduced precision.
+    quantized: Option<Quantized>,
 }
 
 impl Embedding {
-    fn distance(&self, other: &Self) -> f32 {
-        let result: f32 = self
-            .v0
+    pub fn new(v0: Vec<f32>) -> Self {
+        Self {
+            v0,
+            quantized: None,
+        }
+    }
+
+    /// Returns the values with full precision.
+    pub fn values(&self) -> Cow<[f32]> {
+        match &self.quantized {
+            Some(quantized) => Cow::Owned(quantized.dequantize()),
+            None => Cow::Borrowed(&self.v0),
+        }
+    }
+
+    pub fn precision(&self) -> EmbeddingPrecision {
+        self.quantized
+            .as_ref()
+            .map_or(EmbeddingPrecision::F32, |q| q.precision())
+    }
+
+    /// Converts the embedding to the given precision. Converting between two
+    /// reduced precisions loses the accuracy of both.
+    pub fn quantize(&self, precision: EmbeddingPrecision) -> Self {
+        if self.precision() == precision {
+            return self.clone();
+        }
+        let values = self.values();
+        match Quantized::new(&values, precision) {
+            Some(quantized) => Self {
+                v0: vec![],
+                quantized: Some(quantized),
+            },
+            None => Self::new(values.into_owned()),
+        }
+    }
+
+    /// Scales the embedding to unit length so that distances are comparable
+    /// across recognition models.
+    pub fn normalize(mut self) -> Self {
+        let norm = self.v0.iter().map(|a| a * a).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            self.v0.iter_mut().for_each(|a| *a /= norm);
+        }
+        self
+    }
+
+    fn dot(&self, other: &Self) -> f32 {
+        self.v0
             .iter()
             .zip(other.v0.iter())
-            .map(|(a, b)| (a - b) * (a - b))
-            .sum();
-        result.sqrt()
+            .map(|(a, b)| a * b)
+            .sum()
+    }
+
+    /// Returns how far apart the two embeddings are. Lower is closer for all
+    /// metrics: the similarity metrics are negated and shifted so that they
+    /// can be compared against `Config::max_distance`.
+    pub fn distance(&self, other: &Self, metric: DistanceMetric) -> f32 {
+        match (&self.quantized, &other.quantized) {
+            (Some(quantized), None) => return quantized_distance(quantized, &other.v0, metric),
+            (None, Some(quantized)) => return quantized_distance(quantized, &self.v0, metric),
+            (Some(_), Some(_)) => {
+                return Self::new(self.values().into_owned())
+                    .distance(&Self::new(other.values().into_owned()), metric)
+            }
+            (None, None) => {}
+        }
+        match metric {
+            DistanceMetric::Euclidean => {
+                let result: f32 = self
+                    .v0
+                    .iter()
+                    .zip(other.v0.iter())
+                    .map(|(a, b)| (a - b) * (a - b))
+                    .sum();
+                result.sqrt()
+            }
+            DistanceMetric::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|a| a * a).sum::<f32>().sqrt();
+                let norms = norm(&self.v0) * norm(&other.v0);
+                if norms == 0.0 {
+                    return 1.0;
+                }
+                1.0 - self.dot(other) / norms
+            }
+            DistanceMetric::DotProduct => -self.dot(other),
+        }
     }
 }
 
-#[derive(CandidType, Deserialize, Clone)]
-pub struct Person {
-    pub label: String,
-    pub score: f32,
+/// Like `Embedding::distance`, computed from the dot product and the lengths
+/// so that the stored values need not be dequantized.
+fn quantized_distance(quantized: &Quantized, other: &[f32], metric: DistanceMetric) -> f32 {
+    let dot = quantized.dot(other);
+    let norm_squared = quantized.norm_squared();
+    let other_norm_squared: f32 = other.iter().map(|a| a * a).sum();
+    match metric {
+        DistanceMetric::Euclidean => (norm_squared + other_norm_squared - 2.0 * dot)
+            .max(0.0)
+            .sqrt(),
+        DistanceMetric::Cosine => {
+            let norms = (norm_squared * other_norm_squared).sqrt();
+            if norms == 0.0 {
+                return 1.0;
+            }
+            1.0 - dot / norms
+        }
+        DistanceMetric::DotProduct => -dot,
+    }
 }
 
-fn setup_facedetect(bytes: Bytes) -> TractResult<()> {
+impl Storable for Embedding {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// The version of the steps between the uploaded image and the embedding:
+// detection, cropping and alignment. Bump it whenever the steps change.
+const PREPROCESSING_VERSION: u32 = 1;
+
+/// How the embedding of a face was computed. Embeddings computed with
+/// different preprocessing are not comparable, so templates enrolled with
+/// another preprocessing must be enrolled again.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub struct Preprocessing {
+    // See `PREPROCESSING_VERSION`.
+    version: u32,
+    // The `face_margin` the face was cropped with.
+    margin: f32,
+    // Whether the face was aligned using the landmark model.
+    aligned: bool,
+}
+
+impl Preprocessing {
+    /// Returns the preprocessing of faces embedded with the given config and
+    /// the loaded models.
+    pub fn current(config: &Config) -> Self {
+        Self {
+            version: PREPROCESSING_VERSION,
+            margin: config.face_margin(),
+            aligned: landmarks_loaded(),
+        }
+    }
+}
+
+/// The error returned when a model is used before it has been loaded.
+#[derive(Debug)]
+pub struct ModelsNotLoaded;
+
+impl std::fmt::Display for ModelsNotLoaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Models are not loaded yet")
+    }
+}
+
+impl std::error::Error for ModelsNotLoaded {}
+
+/// The reason an image was rejected for enrollment.
+#[derive(Debug)]
+pub enum EnrollmentRejection {
+    NoFace,
+    MultipleFaces(usize),
+    // The fraction of the image covered by the face and the minimum.
+    FaceTooSmall(f32, f32),
+}
+
+impl std::fmt::Display for EnrollmentRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoFace => write!(f, "No face detected"),
+            Self::MultipleFaces(count) => write!(
+                f,
+                "{} faces detected, the image must contain exactly one face",
+                count
+            ),
+            Self::FaceTooSmall(fraction, min) => write!(
+                f,
+                "The face covers {:.1}% of the image, at least {:.1}% is required",
+                fraction * 100.0,
+                min * 100.0
+            ),
+        }
+    }
+}
+
+impl std::error::Error for EnrollmentRejection {}
+
+/// The error returned when the anti-spoofing model considers a face to be a
+/// presentation attack.
+#[derive(Debug)]
+pub struct SpoofSuspected(pub f32);
+
+impl std::fmt::Display for SpoofSuspected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The face does not appear to be live (score {:.3})",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for SpoofSuspected {}
+
+fn load(bytes: Bytes) -> TractResult<Model> {
     let proto: tract_onnx::pb::ModelProto = tract_onnx::pb::ModelProto::decode(bytes)?;
-    let ultraface = tract_onnx::onnx()
+    tract_onnx::onnx()
         .model_for_proto_model(&proto)?
         .into_optimized()?
-        .into_runnable()?;
+        .into_runnable()
+}
+
+/// Loads the face detection model from the given ONNX bytes.
+pub fn setup_facedetect(bytes: Bytes) -> TractResult<()> {
+    let ultraface = load(bytes)?;
     FACE_DETECTION.with_borrow_mut(|m| {
         *m = Some(ultraface);
     });
     Ok(())
 }
 
-fn setup_facerec(bytes: Bytes) -> TractResult<()> {
-    let proto: tract_onnx::pb::ModelProto = tract_onnx::pb::ModelProto::decode(bytes)?;
-    let facerec = tract_onnx::onnx()
-        .model_for_proto_model(&proto)?
-        .into_optimized()?
-        .into_runnable()?;
+/// Loads the face recognition model from the given ONNX bytes.
+pub fn setup_facerec(bytes: Bytes) -> TractResult<()> {
+    let version = model_version(&bytes);
+    let facerec = load(bytes)?;
     FACE_RECOGNITION.with_borrow_mut(|m| {
         *m = Some(facerec);
     });
+    FACE_RECOGNITION_VERSION.with_borrow_mut(|v| *v = Some(version));
+    Ok(())
+}
+
+/// Returns a short hash of the given model bytes, which is recorded on the
+/// people enrolled with the model.
+fn model_version(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Returns the version of the loaded face recognition model, see
+/// `model_version`.
+pub fn recognition_model_version() -> Option<String> {
+    FACE_RECOGNITION_VERSION.with_borrow(|v| v.clone())
+}
+
+/// Loads the face landmark model from the given ONNX bytes.
+pub fn setup_landmarks(bytes: Bytes) -> TractResult<()> {
+    let landmarks = load(bytes)?;
+    FACE_LANDMARKS.with_borrow_mut(|m| {
+        *m = Some(landmarks);
+    });
+    Ok(())
+}
+
+/// Loads the anti-spoofing model from the given ONNX bytes.
+pub fn setup_antispoof(bytes: Bytes) -> TractResult<()> {
+    let antispoof = load(bytes)?;
+    FACE_ANTISPOOF.with_borrow_mut(|m| {
+        *m = Some(antispoof);
+    });
+    Ok(())
+}
+
+/// Loads all models. The models are swapped in only if all of them load
+/// successfully, so a failure leaves the previously loaded models untouched.
+/// Optional models without bytes are unloaded.
+pub fn setup(
+    facedetect: Bytes,
+    facerec: Bytes,
+    landmarks: Option<Bytes>,
+    antispoof: Option<Bytes>,
+) -> TractResult<()> {
+    let ultraface = load(facedetect)?;
+    let version = model_version(&facerec);
+    let facerec = load(facerec)?;
+    let landmarks = landmarks.map(load).transpose()?;
+    let antispoof = antispoof.map(load).transpose()?;
+    FACE_DETECTION.with_borrow_mut(|m| {
+        *m = Some(ultraface);
+    });
+    FACE_RECOGNITION.with_borrow_mut(|m| {
+        *m = Some(facerec);
+    });
+    FACE_RECOGNITION_VERSION.with_borrow_mut(|v| *v = Some(version));
+    FACE_LANDMARKS.with_borrow_mut(|m| {
+        *m = landmarks;
+    });
+    FACE_ANTISPOOF.with_borrow_mut(|m| {
+        *m = antispoof;
+    });
     Ok(())
 }
 
-pub fn setup(facedetect: Bytes, facerec: Bytes) -> TractResult<()> {
-    setup_facedetect(facedetect)?;
-    setup_facerec(facerec)
+/// Returns true if both the face detection and the face recognition models
+/// are loaded.
+pub fn models_loaded() -> bool {
+    FACE_DETECTION.with_borrow(|m| m.is_some()) && FACE_RECOGNITION.with_borrow(|m| m.is_some())
+}
+
+/// Returns true if the face landmarks model is loaded.
+pub fn landmarks_loaded() -> bool {
+    FACE_LANDMARKS.with_borrow(|m| m.is_some())
+}
+
+/// Checks the size of the given encoded image against the configured limits
+/// without decoding the pixels.
+pub fn check_image(image: &[u8], config: &Config) -> Result<(), anyhow::Error> {
+    if image.len() as u64 > config.max_image_bytes {
+        return Err(anyhow!(
+            "The image exceeds the maximum size of {} bytes",
+            config.max_image_bytes
+        ));
+    }
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(image))
+        .with_guessed_format()?
+        .into_dimensions()?;
+    if width > config.max_image_dimension || height > config.max_image_dimension {
+        return Err(anyhow!(
+            "The image exceeds the maximum dimension of {}px",
+            config.max_image_dimension
+        ));
+    }
+    Ok(())
 }
 
 /// Returns a bounding box around the face detected in the given image.
-pub fn detect(image: Vec<u8>) -> Result<(BoundingBox, f32), anyhow::Error> {
+pub fn detect(image: Vec<u8>, config: &Config) -> Result<(BoundingBox, f32), anyhow::Error> {
+    let image = image::load_from_memory(&image)?.to_rgb8();
+    detect_face(&image, config)
+}
+
+/// Returns all faces detected in the given image, most confident first.
+pub fn detect_faces(image: Vec<u8>, config: &Config) -> Result<Vec<FaceDetection>, anyhow::Error> {
+    let image = image::load_from_memory(&image)?.to_rgb8();
+    detect_all(&image, config)
+}
+
+/// Returns the most confident face in the given image.
+fn detect_face(image: &RgbImage, config: &Config) -> Result<(BoundingBox, f32), anyhow::Error> {
+    let best = detect_all(image, config)?
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("No face detected"))?;
+    Ok((best.bounding_box, best.confidence))
+}
+
+/// Runs the detection model and returns the faces above the configured
+/// confidence after non-maximum suppression, most confident first.
+fn detect_all(image: &RgbImage, config: &Config) -> Result<Vec<FaceDetection>, anyhow::Error> {
     FACE_DETECTION.with_borrow(|model| {
-        let model = model.as_ref().unwrap();
-        let image = image::load_from_memory(&image)?.to_rgb8();
+        let model = model.as_ref().ok_or(ModelsNotLoaded)?;
+        let (width, height) = image.dimensions();
 
         // The model accepts an image of size 320x240px.
         let image =
-            image::imageops::resize(&image, 320, 240, ::image::imageops::FilterType::Triangle);
+            image::imageops::resize(image, 320, 240, ::image::imageops::FilterType::Triangle);
 
         const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
         const STD: [f32; 3] = [0.229, 0.224, 0.225];
@@ -113,28 +550,250 @@ pub fn detect(image: Vec<u8>) -> Result<(BoundingBox, f32), anyhow::Error> {
             .to_vec();
 
         let boxes: Vec<_> = result[1].to_array_view::<f32>()?.iter().cloned().collect();
-        let boxes: Vec<_> = boxes.chunks(4).map(BoundingBox::new).collect();
-        let boxes: Vec<_> = boxes.iter().zip(confidences.iter()).collect();
+        let candidates = boxes
+            .chunks(4)
+            .map(BoundingBox::new)
+            .zip(confidences)
+            .filter(|(_, confidence)| *confidence >= config.min_face_confidence)
+            .map(|(candidate, confidence)| FaceDetection {
+                // The model returns boxes relative to the image size.
+                bounding_box: candidate.scale(width as f32, height as f32),
+                confidence,
+            })
+            .collect();
+        Ok(suppress(candidates, config.nms_iou_threshold()))
+    })
+}
 
-        let best = boxes
+/// Keeps the most confident of the detections that overlap by more than the
+/// given intersection over union, most confident first.
+fn suppress(mut candidates: Vec<FaceDetection>, iou_threshold: f32) -> Vec<FaceDetection> {
+    candidates.sort_by(|a, b| f32::total_cmp(&b.confidence, &a.confidence));
+    let mut faces: Vec<FaceDetection> = vec![];
+    for candidate in candidates {
+        if faces
             .iter()
-            .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
-            .ok_or(anyhow!("No face detected"))?;
+            .all(|face| face.bounding_box.iou(&candidate.bounding_box) <= iou_threshold)
+        {
+            faces.push(candidate);
+        }
+    }
+    faces
+}
+
+fn face_fraction(image: &RgbImage, face: &BoundingBox) -> f32 {
+    let (width, height) = image.dimensions();
+    face.area() / (width as f32 * height as f32)
+}
+
+/// Decodes the given image, finds the face in it and assesses its quality.
+/// For enrollment, the image must also pass the enrollment policy, see
+/// `enrollment_face`. Otherwise the most confident face is used.
+pub fn prepare(
+    image: Vec<u8>,
+    config: &Config,
+    purpose: Purpose,
+) -> Result<PreparedFace, anyhow::Error> {
+    let image = image::load_from_memory(&image)?.to_rgb8();
+    let face = match purpose {
+        Purpose::Recognition => detect_face(&image, config)?.0,
+        Purpose::Enrollment => enrollment_face(&image, config)?,
+    };
+    let fraction = face_fraction(&image, &face);
+    let crop = crop(&image, face, config)?;
+    let quality = quality::assess(&crop.image, fraction);
+    Ok(PreparedFace {
+        image,
+        crop,
+        quality,
+    })
+}
+
+/// Returns the only face in the given image if the image is fit for
+/// enrollment: exactly one face above the configured confidence that covers
+/// a large enough part of the image.
+fn enrollment_face(image: &RgbImage, config: &Config) -> Result<BoundingBox, anyhow::Error> {
+    let mut faces = detect_all(image, config)?;
+    let face = match faces.len() {
+        0 => return Err(EnrollmentRejection::NoFace.into()),
+        1 => faces.remove(0).bounding_box,
+        count => return Err(EnrollmentRejection::MultipleFaces(count).into()),
+    };
+    let fraction = face_fraction(image, &face);
+    let min = config.min_face_fraction();
+    if fraction < min {
+        return Err(EnrollmentRejection::FaceTooSmall(fraction, min).into());
+    }
+    Ok(face)
+}
+
+/// Crops the given face out of the image, expanded by the configured margin.
+fn crop(image: &RgbImage, face: BoundingBox, config: &Config) -> Result<FaceCrop, anyhow::Error> {
+    let (width, height) = image.dimensions();
+    let face = face.expand(config.face_margin(), width as f32, height as f32);
+    let x = face.left as u32;
+    let y = face.top as u32;
+    let w = (face.right as u32).saturating_sub(x);
+    let h = (face.bottom as u32).saturating_sub(y);
+    if w == 0 || h == 0 {
+        return Err(anyhow!("No face detected"));
+    }
+    let image = image::imageops::crop_imm(image, x, y, w, h).to_image();
+    Ok(FaceCrop { image, face })
+}
+
+/// Returns the landmarks of the face in the given crop, or `None` if the
+/// landmark model is not loaded.
+fn detect_landmarks(crop: &FaceCrop) -> Result<Option<Landmarks>, anyhow::Error> {
+    FACE_LANDMARKS.with_borrow(|model| {
+        let model = match model.as_ref() {
+            Some(model) => model,
+            None => return Ok(None),
+        };
+
+        // The model accepts an image of size 112x112px and returns the five
+        // points as (x, y) pairs relative to the image size.
+        let image = image::imageops::resize(
+            &crop.image,
+            112,
+            112,
+            ::image::imageops::FilterType::Triangle,
+        );
 
-        let best = (best.0.clone(), best.1.clone());
-        Ok(best)
+        let tensor = tract_ndarray::Array4::from_shape_fn((1, 3, 112, 112), |(_, c, y, x)| {
+            image[(x as u32, y as u32)][c] as f32 / 255.0
+        });
+
+        let result = model.run(tvec!(Tensor::from(tensor).into()))?;
+
+        let raw: Vec<f32> = result[0].to_array_view::<f32>()?.iter().cloned().collect();
+        if raw.len() < 10 {
+            return Err(anyhow!(
+                "The landmark model returned {} values instead of 10",
+                raw.len()
+            ));
+        }
+
+        // Map the points back into the original image.
+        let (width, height) = crop.image.dimensions();
+        let point = |i: usize| Point {
+            x: crop.face.left.floor() + raw[2 * i] * width as f32,
+            y: crop.face.top.floor() + raw[2 * i + 1] * height as f32,
+        };
+        Ok(Some(Landmarks {
+            left_eye: point(0),
+            right_eye: point(1),
+            nose: point(2),
+            left_mouth: point(3),
+            right_mouth: point(4),
+        }))
     })
 }
 
+// Where the landmarks end up in an aligned 112x112px face, as used by ArcFace.
+const ALIGNED_TEMPLATE: [(f32, f32); 5] = [
+    (38.2946, 51.6963),
+    (73.5318, 51.5014),
+    (56.0252, 71.7366),
+    (41.5493, 92.3655),
+    (70.7299, 92.2041),
+];
+
+/// A similarity transform `p -> [a -b; b a] * p + t`.
+struct Similarity {
+    a: f32,
+    b: f32,
+    tx: f32,
+    ty: f32,
+}
+
+impl Similarity {
+    /// Returns the least-squares similarity transform mapping `from` onto `to`.
+    fn estimate(from: &[Point], to: &[Point]) -> Option<Self> {
+        let n = from.len() as f32;
+        let mean = |points: &[Point]| Point {
+            x: points.iter().map(|p| p.x).sum::<f32>() / n,
+            y: points.iter().map(|p| p.y).sum::<f32>() / n,
+        };
+        let (fm, tm) = (mean(from), mean(to));
+        let (mut dot, mut cross, mut norm) = (0.0, 0.0, 0.0);
+        for (f, t) in from.iter().zip(to.iter()) {
+            let (fx, fy) = (f.x - fm.x, f.y - fm.y);
+            let (tx, ty) = (t.x - tm.x, t.y - tm.y);
+            dot += fx * tx + fy * ty;
+            cross += fx * ty - fy * tx;
+            norm += fx * fx + fy * fy;
+        }
+        if norm == 0.0 {
+            return None;
+        }
+        let (a, b) = (dot / norm, cross / norm);
+        Some(Self {
+            a,
+            b,
+            tx: tm.x - (a * fm.x - b * fm.y),
+            ty: tm.y - (b * fm.x + a * fm.y),
+        })
+    }
+
+    fn invert(&self, p: Point) -> Point {
+        let det = self.a * self.a + self.b * self.b;
+        let (x, y) = (p.x - self.tx, p.y - self.ty);
+        Point {
+            x: (self.a * x + self.b * y) / det,
+            y: (self.a * y - self.b * x) / det,
+        }
+    }
+}
+
+/// Samples the given image at a fractional position with bilinear
+/// interpolation, clamping to the image borders.
+fn sample(image: &RgbImage, p: Point) -> image::Rgb<u8> {
+    let (width, height) = image.dimensions();
+    let x = p.x.clamp(0.0, (width - 1) as f32);
+    let y = p.y.clamp(0.0, (height - 1) as f32);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (dx, dy) = (x - x0 as f32, y - y0 as f32);
+    let mut result = [0u8; 3];
+    for (c, value) in result.iter_mut().enumerate() {
+        let top = image[(x0, y0)][c] as f32 * (1.0 - dx) + image[(x1, y0)][c] as f32 * dx;
+        let bottom = image[(x0, y1)][c] as f32 * (1.0 - dx) + image[(x1, y1)][c] as f32 * dx;
+        *value = (top * (1.0 - dy) + bottom * dy).round() as u8;
+    }
+    image::Rgb(result)
+}
+
+/// Warps the face with the given landmarks so that the landmarks land on the
+/// canonical template in a `size`x`size` image.
+fn align(image: &RgbImage, landmarks: &Landmarks, size: u32) -> Result<RgbImage, anyhow::Error> {
+    let scale = size as f32 / 112.0;
+    let template: Vec<_> = ALIGNED_TEMPLATE
+        .iter()
+        .map(|(x, y)| Point {
+            x: x * scale,
+            y: y * scale,
+        })
+        .collect();
+    let transform = Similarity::estimate(&landmarks.points(), &template)
+        .ok_or(anyhow!("Failed to align the face"))?;
+    Ok(RgbImage::from_fn(size, size, |x, y| {
+        let p = transform.invert(Point {
+            x: x as f32,
+            y: y as f32,
+        });
+        sample(image, p)
+    }))
+}
+
 /// Computes a face embedding corresponding to the given image of a face.
-pub fn embedding(image: Vec<u8>) -> Result<Embedding, anyhow::Error> {
+fn embedding(image: &RgbImage) -> Result<Embedding, anyhow::Error> {
     FACE_RECOGNITION.with_borrow(|model| {
-        let model = model.as_ref().unwrap();
-        let image = image::load_from_memory(&image)?.to_rgb8();
+        let model = model.as_ref().ok_or(ModelsNotLoaded)?;
 
         // The model accepts an image of size 160x160px.
         let image =
-            image::imageops::resize(&image, 160, 160, ::image::imageops::FilterType::Triangle);
+            image::imageops::resize(image, 160, 160, ::image::imageops::FilterType::Triangle);
 
         let tensor = tract_ndarray::Array4::from_shape_fn((1, 3, 160, 160), |(_, c, y, x)| {
             image[(x as u32, y as u32)][c] as f32 / 255.0
@@ -148,34 +807,255 @@ pub fn embedding(image: Vec<u8>) -> Result<Embedding, anyhow::Error> {
             .cloned()
             .collect();
 
-        Ok(Embedding { v0 })
+        Ok(Embedding::new(v0).normalize())
     })
 }
 
-/// Returns the person whose face embedding is the closest to the face embedding
-/// of the given image.
-pub fn recognize(image: Vec<u8>) -> Result<Person, anyhow::Error> {
-    let emb = embedding(image)?;
-    DB.with_borrow(|db| {
-        let emb = &emb;
-        let best = db
-            .iter()
-            .min_by(|a, b| f32::partial_cmp(&a.1.distance(emb), &b.1.distance(emb)).unwrap());
-        let best = best.ok_or(anyhow!("Unknown person"))?.clone();
-        let label = best.0;
-        let score = best.1.distance(emb);
-        if score > THRESHOLD {
-            return Err(anyhow!("Unknown person"));
-        }
-        Ok(Person { label, score })
+/// Returns the probability that the given face is live, or `None` if the
+/// anti-spoofing model is not loaded.
+fn liveness(crop: &FaceCrop) -> Result<Option<f32>, anyhow::Error> {
+    FACE_ANTISPOOF.with_borrow(|model| {
+        let model = match model.as_ref() {
+            Some(model) => model,
+            None => return Ok(None),
+        };
+
+        // The model accepts an image of size 80x80px and returns one logit
+        // per class, where class 1 is a live face.
+        let image =
+            image::imageops::resize(&crop.image, 80, 80, ::image::imageops::FilterType::Triangle);
+
+        let tensor = tract_ndarray::Array4::from_shape_fn((1, 3, 80, 80), |(_, c, y, x)| {
+            image[(x as u32, y as u32)][c] as f32 / 255.0
+        });
+
+        let result = model.run(tvec!(Tensor::from(tensor).into()))?;
+
+        let logits: Vec<f32> = result[0].to_array_view::<f32>()?.iter().cloned().collect();
+        if logits.len() < 2 {
+            return Err(anyhow!(
+                "The anti-spoofing model returned {} values instead of at least 2",
+                logits.len()
+            ));
+        }
+        let max = logits.iter().cloned().fold(f32::MIN, f32::max);
+        let sum: f32 = logits.iter().map(|l| (l - max).exp()).sum();
+        Ok(Some((logits[1] - max).exp() / sum))
     })
 }
 
-/// Records a new person with the given name and face image into the state.
-pub fn add(label: String, image: Vec<u8>) -> Result<Embedding, anyhow::Error> {
-    let emb = embedding(image)?;
-    DB.with_borrow_mut(|db| {
-        db.push((label, emb.clone()));
-    });
-    Ok(emb)
+/// Runs the anti-spoofing model on the given face and fails if the face is
+/// suspected to be a spoof. Fails closed if a liveness threshold is
+/// configured but the model is not loaded.
+fn check_liveness(face: &PreparedFace, config: &Config) -> Result<Option<f32>, anyhow::Error> {
+    let score = liveness(&face.crop)?;
+    if let Some(min) = config.min_liveness {
+        let score = score.ok_or(anyhow!("The anti-spoofing model is not loaded"))?;
+        if score < min {
+            return Err(SpoofSuspected(score).into());
+        }
+    }
+    Ok(score)
+}
+
+/// Computes the embedding of the given face, aligning the face in the
+/// original image if the landmark model is loaded.
+pub fn embed_face(face: PreparedFace) -> Result<(Embedding, DetectedFace), anyhow::Error> {
+    let PreparedFace { image, crop, .. } = face;
+    let landmarks = detect_landmarks(&crop)?;
+    let emb = match &landmarks {
+        // The recognition model accepts an image of size 160x160px.
+        Some(landmarks) => embedding(&align(&image, landmarks, 160)?)?,
+        None => embedding(&crop.image)?,
+    };
+    let face = DetectedFace {
+        bounding_box: crop.face,
+        landmarks,
+        liveness: None,
+    };
+    Ok((emb, face))
+}
+
+/// Checks the liveness of the given face and computes its embedding.
+pub fn analyze(
+    face: PreparedFace,
+    config: &Config,
+) -> Result<(Embedding, DetectedFace), anyhow::Error> {
+    let liveness = check_liveness(&face, config)?;
+    let (emb, mut face) = embed_face(face)?;
+    face.liveness = liveness;
+    Ok((emb, face))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounding_box(left: f32, top: f32, right: f32, bottom: f32) -> BoundingBox {
+        BoundingBox::new(&[left, top, right, bottom])
+    }
+
+    fn detection(left: f32, confidence: f32) -> FaceDetection {
+        FaceDetection {
+            bounding_box: bounding_box(left, 0.0, left + 10.0, 10.0),
+            confidence,
+        }
+    }
+
+    #[test]
+    fn iou_of_boxes() {
+        let a = bounding_box(0.0, 0.0, 10.0, 10.0);
+        assert_eq!(a.iou(&a), 1.0);
+        assert_eq!(a.iou(&bounding_box(20.0, 0.0, 30.0, 10.0)), 0.0);
+        // Half of each box overlaps: 50 / (100 + 100 - 50).
+        let b = bounding_box(5.0, 0.0, 15.0, 10.0);
+        assert!((a.iou(&b) - 1.0 / 3.0).abs() < 1e-6);
+        assert_eq!(a.iou(&b), b.iou(&a));
+        assert_eq!(a.iou(&bounding_box(5.0, 5.0, 5.0, 5.0)), 0.0);
+    }
+
+    #[test]
+    fn expands_within_the_image() {
+        let face = bounding_box(10.0, 20.0, 30.0, 60.0).expand(0.5, 100.0, 100.0);
+        assert_eq!(
+            (face.left, face.top, face.right, face.bottom),
+            (0.0, 0.0, 40.0, 80.0)
+        );
+        let face = bounding_box(80.0, 80.0, 90.0, 90.0).expand(0.5, 92.0, 100.0);
+        assert_eq!(
+            (face.left, face.top, face.right, face.bottom),
+            (75.0, 75.0, 92.0, 95.0)
+        );
+    }
+
+    #[test]
+    fn suppresses_overlapping_detections() {
+        let faces = suppress(
+            vec![
+                detection(0.0, 0.7),
+                detection(1.0, 0.9),
+                detection(50.0, 0.8),
+            ],
+            0.3,
+        );
+        let kept: Vec<(f32, f32)> = faces
+            .iter()
+            .map(|face| (face.bounding_box.left, face.confidence))
+            .collect();
+        assert_eq!(kept, vec![(1.0, 0.9), (50.0, 0.8)]);
+    }
+
+    #[test]
+    fn keeps_detections_below_the_threshold() {
+        // The two boxes overlap with an intersection over union of 1/3.
+        let candidates = vec![detection(0.0, 0.9), detection(5.0, 0.8)];
+        assert_eq!(suppress(candidates.clone(), 0.5).len(), 2);
+        assert_eq!(suppress(candidates, 0.2).len(), 1);
+    }
+
+    #[test]
+    fn sorts_nan_confidences_without_panicking() {
+        let faces = suppress(vec![detection(0.0, f32::NAN), detection(50.0, 0.8)], 0.3);
+        assert_eq!(faces.len(), 2);
+    }
+
+    fn embedding(values: &[f32]) -> Embedding {
+        Embedding::new(values.to_vec())
+    }
+
+    #[test]
+    fn measures_distances() {
+        let (a, b) = (embedding(&[3.0, 0.0]), embedding(&[0.0, 4.0]));
+        assert_eq!(a.distance(&b, DistanceMetric::Euclidean), 5.0);
+        assert_eq!(a.distance(&b, DistanceMetric::Cosine), 1.0);
+        assert_eq!(a.distance(&a, DistanceMetric::Cosine), 0.0);
+        assert_eq!(a.distance(&a, DistanceMetric::DotProduct), -9.0);
+        assert_eq!(
+            a.distance(&embedding(&[0.0, 0.0]), DistanceMetric::Cosine),
+            1.0
+        );
+    }
+
+    #[test]
+    fn normalizes_to_unit_length() {
+        let normalized = embedding(&[3.0, 4.0]).normalize();
+        assert_eq!(&*normalized.values(), &[0.6, 0.8]);
+        let zero = embedding(&[0.0, 0.0]).normalize();
+        assert_eq!(&*zero.values(), &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn quantized_distances_match_the_dequantized_ones() {
+        let a = embedding(&[0.3, -0.5, 0.8, 0.1]).normalize();
+        let b = embedding(&[-0.2, 0.4, 0.7, -0.6]).normalize();
+        for precision in [EmbeddingPrecision::F16, EmbeddingPrecision::Int8] {
+            let quantized = a.quantize(precision);
+            let dequantized = embedding(&quantized.values());
+            for metric in [
+                DistanceMetric::Euclidean,
+                DistanceMetric::Cosine,
+                DistanceMetric::DotProduct,
+            ] {
+                let expected = dequantized.distance(&b, metric);
+                assert!((quantized.distance(&b, metric) - expected).abs() < 1e-4);
+                assert!((b.distance(&quantized, metric) - expected).abs() < 1e-4);
+            }
+        }
+    }
+
+    fn template() -> Vec<Point> {
+        ALIGNED_TEMPLATE
+            .iter()
+            .map(|&(x, y)| Point { x, y })
+            .collect()
+    }
+
+    fn transform(similarity: &Similarity, p: Point) -> Point {
+        Point {
+            x: similarity.a * p.x - similarity.b * p.y + similarity.tx,
+            y: similarity.b * p.x + similarity.a * p.y + similarity.ty,
+        }
+    }
+
+    #[test]
+    fn estimates_a_similarity() {
+        // Scale by 2, rotate by 30 degrees and translate.
+        let expected = Similarity {
+            a: 2.0 * 30f32.to_radians().cos(),
+            b: 2.0 * 30f32.to_radians().sin(),
+            tx: 5.0,
+            ty: -3.0,
+        };
+        let from = template();
+        let to: Vec<Point> = from.iter().map(|&p| transform(&expected, p)).collect();
+        let estimated = Similarity::estimate(&from, &to).unwrap();
+        for (value, expected) in [
+            (estimated.a, expected.a),
+            (estimated.b, expected.b),
+            (estimated.tx, expected.tx),
+            (estimated.ty, expected.ty),
+        ] {
+            assert!((value - expected).abs() < 1e-3, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn inverts_a_similarity() {
+        let similarity = Similarity {
+            a: 0.5,
+            b: -1.2,
+            tx: 10.0,
+            ty: 4.0,
+        };
+        for p in template() {
+            let back = similarity.invert(transform(&similarity, p));
+            assert!((back.x - p.x).abs() < 1e-3 && (back.y - p.y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn does_not_estimate_from_a_single_point() {
+        let points = vec![Point { x: 1.0, y: 2.0 }; 5];
+        assert!(Similarity::estimate(&points, &template()).is_none());
+    }
 }
@@ -0,0 +1,227 @@
+use crate::Memory;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use unicode_normalization::UnicodeNormalization;
+
+thread_local! {
+    static PEOPLE: RefCell<StableBTreeMap<PersonId, PersonRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(crate::memory(crate::PEOPLE_MEMORY_ID)));
+    // The id of the next person, so that the ids of removed people are not
+    // given to new ones.
+    static NEXT_ID: RefCell<StableCell<PersonId, Memory>> = RefCell::new(
+        StableCell::init(crate::memory(crate::PEOPLE_NEXT_ID_MEMORY_ID), 0)
+            .expect("failed to initialize the next person id"),
+    );
+}
+
+pub type PersonId = u64;
+
+const MAX_LABEL_CHARS: usize = 64;
+const MAX_ATTRIBUTES: usize = 16;
+const MAX_ATTRIBUTE_KEY_CHARS: usize = 64;
+const MAX_ATTRIBUTE_VALUE_CHARS: usize = 256;
+
+/// A person in the gallery. The templates of the person refer to it by id, so
+/// people with the same label are kept apart.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PersonRecord {
+    pub id: PersonId,
+    // The display name, see `normalize_label`.
+    pub label: String,
+    // The principal that enrolled the person.
+    pub principal: Principal,
+    pub created_at: u64,
+    // When the label, the attributes or the templates last changed.
+    pub updated_at: u64,
+    // The version of the recognition model the first templates were
+    // computed with.
+    pub model_version: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Storable for PersonRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Returns the given label trimmed and in Unicode normalization form C, so
+/// that labels that look the same compare equal, or an error if the label is
+/// empty, too long or contains control characters.
+pub fn normalize_label(label: &str) -> Result<String, String> {
+    let label: String = label.trim().nfc().collect();
+    if label.is_empty() {
+        return Err("The label must not be empty".to_string());
+    }
+    if label.chars().count() > MAX_LABEL_CHARS {
+        return Err(format!(
+            "The label must not be longer than {} characters",
+            MAX_LABEL_CHARS
+        ));
+    }
+    if label.chars().any(char::is_control) {
+        return Err("The label must not contain control characters".to_string());
+    }
+    Ok(label)
+}
+
+fn validate_attributes(attributes: &[(String, String)]) -> Result<(), String> {
+    if attributes.len() > MAX_ATTRIBUTES {
+        return Err(format!("At most {} attributes are allowed", MAX_ATTRIBUTES));
+    }
+    for (i, (key, value)) in attributes.iter().enumerate() {
+        if key.is_empty() || key.chars().count() > MAX_ATTRIBUTE_KEY_CHARS {
+            return Err(format!(
+                "Attribute names must have between 1 and {} characters",
+                MAX_ATTRIBUTE_KEY_CHARS
+            ));
+        }
+        if value.chars().count() > MAX_ATTRIBUTE_VALUE_CHARS {
+            return Err(format!(
+                "Attribute values must not be longer than {} characters",
+                MAX_ATTRIBUTE_VALUE_CHARS
+            ));
+        }
+        if attributes[..i].iter().any(|(other, _)| other == key) {
+            return Err(format!("The attribute {} is given more than once", key));
+        }
+    }
+    Ok(())
+}
+
+/// Stores a new person with the given label, which is normalized first.
+pub fn create(
+    label: &str,
+    principal: Principal,
+    model_version: String,
+    now: u64,
+) -> Result<PersonRecord, String> {
+    let label = normalize_label(label)?;
+    PEOPLE.with_borrow_mut(|people| {
+        let id = NEXT_ID.with_borrow_mut(crate::next_id);
+        let record = PersonRecord {
+            id,
+            label,
+            principal,
+            created_at: now,
+            updated_at: now,
+            model_version,
+            attributes: vec![],
+        };
+        people.insert(id, record.clone());
+        Ok(record)
+    })
+}
+
+pub fn get(id: PersonId) -> Option<PersonRecord> {
+    PEOPLE.with_borrow(|people| people.get(&id))
+}
+
+pub fn count() -> u64 {
+    PEOPLE.with_borrow(|people| people.len())
+}
+
+/// Returns up to `limit` people ordered by id, skipping the first `offset`.
+pub fn page(offset: usize, limit: usize) -> Vec<PersonRecord> {
+    PEOPLE.with_borrow(|people| {
+        people
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+/// Returns the people enrolled by the given principal.
+pub fn of_principal(principal: &Principal) -> Vec<PersonRecord> {
+    PEOPLE.with_borrow(|people| {
+        people
+            .iter()
+            .map(|(_, record)| record)
+            .filter(|record| record.principal == *principal)
+            .collect()
+    })
+}
+
+fn update(
+    id: PersonId,
+    now: u64,
+    change: impl FnOnce(&mut PersonRecord),
+) -> Result<PersonRecord, String> {
+    PEOPLE.with_borrow_mut(|people| {
+        let mut record = people
+            .get(&id)
+            .ok_or_else(|| format!("Person {} does not exist", id))?;
+        change(&mut record);
+        record.updated_at = now;
+        people.insert(id, record.clone());
+        Ok(record)
+    })
+}
+
+pub fn relabel(id: PersonId, label: &str, now: u64) -> Result<PersonRecord, String> {
+    let label = normalize_label(label)?;
+    update(id, now, |record| record.label = label)
+}
+
+pub fn set_attributes(
+    id: PersonId,
+    attributes: Vec<(String, String)>,
+    now: u64,
+) -> Result<PersonRecord, String> {
+    validate_attributes(&attributes)?;
+    update(id, now, |record| record.attributes = attributes)
+}
+
+/// Records that the templates of the given person changed.
+pub fn touch(id: PersonId, now: u64) {
+    let _ = update(id, now, |_| {});
+}
+
+pub fn remove(id: PersonId) -> Option<PersonRecord> {
+    PEOPLE.with_borrow_mut(|people| people.remove(&id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_label_trims_and_composes() {
+        assert_eq!(normalize_label("  Ada Lovelace\n").unwrap(), "Ada Lovelace");
+        // "e" followed by a combining acute accent becomes a single "é".
+        assert_eq!(normalize_label("Rene\u{301}").unwrap(), "Ren\u{e9}");
+        assert_eq!(
+            normalize_label("Rene\u{301}").unwrap(),
+            normalize_label("Ren\u{e9}").unwrap()
+        );
+    }
+
+    #[test]
+    fn normalize_label_rejects_empty_labels() {
+        assert!(normalize_label("").is_err());
+        assert!(normalize_label(" \t\n").is_err());
+    }
+
+    #[test]
+    fn normalize_label_limits_the_length_in_characters() {
+        let longest = "\u{e9}".repeat(MAX_LABEL_CHARS);
+        assert_eq!(normalize_label(&longest).unwrap(), longest);
+        assert!(normalize_label(&"a".repeat(MAX_LABEL_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn normalize_label_rejects_control_characters() {
+        assert!(normalize_label("Ada\u{0}Lovelace").is_err());
+        assert!(normalize_label("Ada\nLovelace").is_err());
+    }
+}
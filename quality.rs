@@ -0,0 +1,187 @@
+use crate::config::Config;
+use crate::onnx::Purpose;
+use candid::CandidType;
+use image::RgbImage;
+use serde::Deserialize;
+
+/// Quality measures of a face image. Brightness and contrast are in [0, 1].
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Quality {
+    // The variance of the Laplacian of the face. Blurry faces score low.
+    pub sharpness: f32,
+    // The mean luma of the face.
+    pub brightness: f32,
+    // The standard deviation of the luma of the face.
+    pub contrast: f32,
+    // The fraction of the image area covered by the face.
+    pub face_fraction: f32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QualityIssue {
+    TooBlurry,
+    TooDark,
+    TooBright,
+    LowContrast,
+    FaceTooSmall,
+}
+
+/// The error returned when an image does not pass the quality thresholds.
+#[derive(Debug)]
+pub struct LowQuality(pub Vec<QualityIssue>);
+
+impl std::fmt::Display for LowQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The image quality is too low: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for LowQuality {}
+
+fn luma(image: &RgbImage) -> Vec<f32> {
+    image
+        .pixels()
+        .map(|p| (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.0)
+        .collect()
+}
+
+fn mean_and_variance(values: &[f32]) -> (f32, f32) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    (mean, variance)
+}
+
+/// Computes the quality of the given face crop.
+pub fn assess(face: &RgbImage, face_fraction: f32) -> Quality {
+    let (width, height) = face.dimensions();
+    let luma = luma(face);
+    let (brightness, variance) = mean_and_variance(&luma);
+
+    // Apply the 3x3 Laplacian kernel to the inner pixels. Luma is scaled back
+    // to [0, 255] so that the sharpness matches the usual OpenCV numbers.
+    let at = |x: u32, y: u32| luma[(y * width + x) as usize] * 255.0;
+    let mut laplacian = vec![];
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            laplacian
+                .push(at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y));
+        }
+    }
+    let (_, sharpness) = mean_and_variance(&laplacian);
+
+    Quality {
+        sharpness,
+        brightness,
+        contrast: variance.sqrt(),
+        face_fraction,
+    }
+}
+
+/// Returns the configured thresholds the given quality does not meet. The
+/// face size is only enforced for enrollment, see `min_face_fraction`.
+pub fn issues(quality: &Quality, config: &Config, purpose: Purpose) -> Vec<QualityIssue> {
+    let mut issues = vec![];
+    if config
+        .min_sharpness
+        .is_some_and(|min| quality.sharpness < min)
+    {
+        issues.push(QualityIssue::TooBlurry);
+    }
+    if config
+        .min_brightness
+        .is_some_and(|min| quality.brightness < min)
+    {
+        issues.push(QualityIssue::TooDark);
+    }
+    if config
+        .max_brightness
+        .is_some_and(|max| quality.brightness > max)
+    {
+        issues.push(QualityIssue::TooBright);
+    }
+    if config
+        .min_contrast
+        .is_some_and(|min| quality.contrast < min)
+    {
+        issues.push(QualityIssue::LowContrast);
+    }
+    if matches!(purpose, Purpose::Enrollment) && quality.face_fraction < config.min_face_fraction()
+    {
+        issues.push(QualityIssue::FaceTooSmall);
+    }
+    issues
+}
+
+/// Returns an error unless the given quality meets the configured thresholds.
+pub fn check(quality: &Quality, config: &Config, purpose: Purpose) -> Result<(), LowQuality> {
+    let issues = issues(quality, config, purpose);
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(LowQuality(issues))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quality(face_fraction: f32) -> Quality {
+        Quality {
+            sharpness: 100.0,
+            brightness: 0.5,
+            contrast: 0.2,
+            face_fraction,
+        }
+    }
+
+    #[test]
+    fn flat_image_is_neither_sharp_nor_contrasted() {
+        let quality = assess(&RgbImage::from_pixel(8, 8, image::Rgb([128; 3])), 0.5);
+        assert_eq!(quality.sharpness, 0.0);
+        assert!(quality.contrast.abs() < 1e-6);
+        assert!((quality.brightness - 128.0 / 255.0).abs() < 1e-6);
+        assert_eq!(quality.face_fraction, 0.5);
+    }
+
+    #[test]
+    fn checkerboard_is_sharp_and_contrasted() {
+        let checkerboard =
+            RgbImage::from_fn(8, 8, |x, y| image::Rgb([255 * ((x + y) % 2) as u8; 3]));
+        let quality = assess(&checkerboard, 0.5);
+        assert!(quality.sharpness > 1000.0);
+        assert!((quality.contrast - 0.5).abs() < 1e-6);
+        assert!((quality.brightness - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn reports_the_thresholds_that_are_not_met() {
+        let config = Config {
+            min_sharpness: Some(200.0),
+            min_contrast: Some(0.1),
+            ..Config::default()
+        };
+        assert_eq!(
+            issues(&quality(0.5), &config, Purpose::Recognition),
+            vec![QualityIssue::TooBlurry]
+        );
+        assert!(check(&quality(0.5), &Config::default(), Purpose::Recognition).is_ok());
+    }
+
+    #[test]
+    fn only_requires_the_face_size_for_enrollment() {
+        let config = Config {
+            min_face_fraction: Some(0.1),
+            ..Config::default()
+        };
+        assert_eq!(
+            issues(&quality(0.05), &config, Purpose::Enrollment),
+            vec![QualityIssue::FaceTooSmall]
+        );
+        assert!(issues(&quality(0.05), &config, Purpose::Recognition).is_empty());
+    }
+}
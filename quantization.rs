@@ -0,0 +1,229 @@
+use crate::config::EmbeddingPrecision;
+use candid::CandidType;
+use serde::Deserialize;
+
+/// The values of an embedding stored with reduced precision.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Quantized {
+    // IEEE 754 half precision numbers.
+    F16(Vec<u16>),
+    // Each value is `offset + scale * q` for the byte `q`.
+    Int8 {
+        offset: f32,
+        scale: f32,
+        values: Vec<u8>,
+    },
+}
+
+/// The accuracy and size of gallery templates stored with reduced precision,
+/// compared to full precision.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Report {
+    pub precision: EmbeddingPrecision,
+    // The mean encoded size of a template in bytes.
+    pub f32_bytes: u64,
+    pub quantized_bytes: u64,
+    // The error of the distances between pairs of templates.
+    pub mean_distance_error: f32,
+    pub max_distance_error: f32,
+    // The fraction of template pairs whose match decision does not change.
+    pub agreement: f32,
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent >= 31 {
+        // Too large: store infinity. Embeddings are normalized, so this does
+        // not happen in practice.
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // A subnormal number without the implicit leading bit.
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+    // Rounding may carry into the exponent, which is still correct.
+    let half = sign | ((exponent as u16) << 10) | (mantissa >> 13) as u16;
+    half + ((mantissa >> 12) & 1) as u16
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+impl Quantized {
+    /// Stores the given values with the given precision, or returns `None` for
+    /// full precision.
+    pub fn new(values: &[f32], precision: EmbeddingPrecision) -> Option<Self> {
+        match precision {
+            EmbeddingPrecision::F32 => None,
+            EmbeddingPrecision::F16 => Some(Self::F16(
+                values.iter().map(|&value| f32_to_f16(value)).collect(),
+            )),
+            EmbeddingPrecision::Int8 => {
+                let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let (offset, scale) = if values.is_empty() {
+                    (0.0, 1.0)
+                } else if max <= min {
+                    (min, 1.0)
+                } else {
+                    (min, (max - min) / 255.0)
+                };
+                Some(Self::Int8 {
+                    offset,
+                    scale,
+                    values: values
+                        .iter()
+                        .map(|value| ((value - offset) / scale).round().clamp(0.0, 255.0) as u8)
+                        .collect(),
+                })
+            }
+        }
+    }
+
+    pub fn precision(&self) -> EmbeddingPrecision {
+        match self {
+            Self::F16(_) => EmbeddingPrecision::F16,
+            Self::Int8 { .. } => EmbeddingPrecision::Int8,
+        }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        match self {
+            Self::F16(values) => values.iter().map(|&half| f16_to_f32(half)).collect(),
+            Self::Int8 {
+                offset,
+                scale,
+                values,
+            } => values.iter().map(|&q| offset + scale * q as f32).collect(),
+        }
+    }
+
+    /// Returns the dot product with the given full precision values without
+    /// dequantizing.
+    pub fn dot(&self, other: &[f32]) -> f32 {
+        match self {
+            Self::F16(values) => values
+                .iter()
+                .zip(other.iter())
+                .map(|(&half, b)| f16_to_f32(half) * b)
+                .sum(),
+            Self::Int8 {
+                offset,
+                scale,
+                values,
+            } => {
+                let sum: f32 = other.iter().sum();
+                let weighted: f32 = values
+                    .iter()
+                    .zip(other.iter())
+                    .map(|(&q, b)| q as f32 * b)
+                    .sum();
+                offset * sum + scale * weighted
+            }
+        }
+    }
+
+    /// Returns the squared length of the stored values.
+    pub fn norm_squared(&self) -> f32 {
+        match self {
+            Self::F16(values) => values.iter().map(|&half| f16_to_f32(half).powi(2)).sum(),
+            Self::Int8 {
+                offset,
+                scale,
+                values,
+            } => {
+                let n = values.len() as f32;
+                let sum: u64 = values.iter().map(|&q| q as u64).sum();
+                let squares: u64 = values.iter().map(|&q| (q as u64).pow(2)).sum();
+                n * offset * offset
+                    + 2.0 * offset * scale * sum as f32
+                    + scale * scale * squares as f32
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<f32> {
+        (0..128)
+            .map(|i| ((i * 37) % 101) as f32 / 50.0 - 1.0)
+            .collect()
+    }
+
+    fn max_error(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn f32_is_not_quantized() {
+        assert!(Quantized::new(&values(), EmbeddingPrecision::F32).is_none());
+    }
+
+    #[test]
+    fn f16_round_trips() {
+        let values = values();
+        let quantized = Quantized::new(&values, EmbeddingPrecision::F16).unwrap();
+        assert_eq!(quantized.precision(), EmbeddingPrecision::F16);
+        // Half precision keeps 11 significant bits.
+        assert!(max_error(&values, &quantized.dequantize()) <= 1.0 / 2048.0);
+        for value in [0.0, 1.0, -0.5, 0.25, 65504.0] {
+            assert_eq!(f16_to_f32(f32_to_f16(value)), value);
+        }
+        assert_eq!(f16_to_f32(f32_to_f16(1e6)), f32::INFINITY);
+    }
+
+    #[test]
+    fn int8_round_trips() {
+        let values = values();
+        let quantized = Quantized::new(&values, EmbeddingPrecision::Int8).unwrap();
+        assert_eq!(quantized.precision(), EmbeddingPrecision::Int8);
+        // The values span [-1, 1], so each step is 2 / 255 and rounding is off
+        // by at most half a step.
+        assert!(max_error(&values, &quantized.dequantize()) <= 1.0 / 255.0 + 1e-6);
+    }
+
+    #[test]
+    fn int8_keeps_constant_values() {
+        let values = vec![0.5; 8];
+        let quantized = Quantized::new(&values, EmbeddingPrecision::Int8).unwrap();
+        assert_eq!(quantized.dequantize(), values);
+    }
+
+    #[test]
+    fn dot_and_norm_match_dequantized_values() {
+        let values = values();
+        let other: Vec<f32> = values.iter().rev().cloned().collect();
+        for precision in [EmbeddingPrecision::F16, EmbeddingPrecision::Int8] {
+            let quantized = Quantized::new(&values, precision).unwrap();
+            let dequantized = quantized.dequantize();
+            let dot: f32 = dequantized.iter().zip(&other).map(|(a, b)| a * b).sum();
+            let norm_squared: f32 = dequantized.iter().map(|a| a * a).sum();
+            assert!((quantized.dot(&other) - dot).abs() < 1e-3);
+            assert!((quantized.norm_squared() - norm_squared).abs() < 1e-3);
+        }
+    }
+}
@@ -5,6 +5,10 @@ pub fn bytes(filename: &str) -> Bytes {
     std::fs::read(filename).unwrap().into()
 }
 
+pub fn exists(filename: &str) -> bool {
+    std::path::Path::new(filename).exists()
+}
+
 pub fn append_bytes(filename: &str, bytes: Vec<u8>) {
     let mut file = std::fs::OpenOptions::new()
         .create(true)